@@ -6,8 +6,9 @@ fn main() -> Result<(), ainoio_agent::AinoError> {
     // Read the configuration
     let config = ainoio_agent::AinoConfig::new()?;
 
-    // Start the Aino.io agent, this must be done only once
-    ainoio_agent::start(config)?;
+    // Start the Aino.io agent before sending any transactions. It can be started again only after it has stopped.
+    // The pending transactions are sent and the agent is stopped when the guard is dropped at the end of main.
+    let _guard = ainoio_agent::start_with_guard(config)?;
 
     // Spawn two threads that start sending transactions to Aino.io
     // Remember to update the configuration with your actual API key
@@ -29,7 +30,7 @@ fn sender() {
     let id1: &str = "ID1";
     let id2: &str = "ID2";
 
-    loop {
+    for _ in 0..100 {
         match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
             Ok(n) => {
                 // Construct the transaction
@@ -1,296 +1,1132 @@
 use crate::aino_config::AinoConfig;
-use crate::{AinoError, Transaction};
-use futures::future::join_all;
-use std::cmp::min;
-use std::collections::VecDeque;
+use crate::circuit_breaker::CircuitState;
+use crate::queue::{Admission, TransactionQueue};
+use crate::receipt::{DeliveryHandle, DeliveryOutcome};
+use crate::worker::{Msg, ThreadMsg, Worker};
+use crate::{AinoError, AinoErrorKind, Route, Transaction, TryAddError};
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
 use std::sync::mpsc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
 use std::thread;
-use std::time::Instant;
-use tokio::runtime::Runtime;
+use std::time::{Duration, Instant};
+use tokio::runtime::{Handle, Runtime};
+use tokio::sync::mpsc::error::SendError;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 
-enum Msg {
-    Cancel,
-    Trx(Box<Transaction>),
+/// How long a dropped [`AgentGuard`](struct.AgentGuard.html) waits for the pending `Transaction`s by default.
+const GUARD_STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The result of stopping the agent with [`stop_with_timeout`](fn.stop_with_timeout.html).
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct StopOutcome {
+    /// The number of [`Transaction`](struct.Transaction.html)s delivered since the agent was started.
+    pub delivered: u64,
+
+    /// The number of `Transaction`s stored in the spool for the next run, including the ones
+    /// replayed from it that are still there.
+    pub spooled: u64,
+
+    /// The `Transaction`s that were neither delivered before the timeout nor stored in the spool,
+    /// oldest first for each destination.
+    pub undelivered: Vec<Transaction>,
+}
+
+/// Stops the agent it was created for when dropped.
+///
+/// Dropping the guard sends the pending [`Transaction`](struct.Transaction.html)s and stops the
+/// agent like [`stop_with_timeout`](fn.stop_with_timeout.html), so keep it alive until the end of
+/// `main`. The `Transaction`s that could not be delivered in time are stored in the spool, if one
+/// is configured, and otherwise written to the standard output as JSON lines.
+#[must_use = "the agent is stopped as soon as the guard is dropped"]
+pub struct AgentGuard {
+    agent: Agent,
+    timeout: Duration,
+    stopped: bool,
+}
+
+impl AgentGuard {
+    /// Sets how long dropping the guard waits for the pending `Transaction`s. Defaults to five seconds.
+    pub fn with_stop_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Stops the agent right away, handing back the `Transaction`s that were not delivered in time.
+    pub fn stop(mut self) -> Result<StopOutcome, AinoError> {
+        self.stopped = true;
+        self.agent.stop_with_timeout(self.timeout)
+    }
+}
+
+impl Drop for AgentGuard {
+    fn drop(&mut self) {
+        if self.stopped {
+            return;
+        }
+        match self.agent.stop_with_timeout(self.timeout) {
+            Ok(outcome) => log_undelivered(&outcome.undelivered),
+            Err(e) => println!("{}", e),
+        }
+    }
 }
 
-enum ThreadMsg {
-    Finished,
+/// Writes the `Transaction`s that could be neither delivered nor spooled to the standard output,
+/// so that they can still be recovered from the logs.
+fn log_undelivered(undelivered: &[Transaction]) {
+    if undelivered.is_empty() {
+        return;
+    }
+    println!(
+        "Aino error: {} transactions were not delivered before the agent stopped:",
+        undelivered.len()
+    );
+    for transaction in undelivered {
+        match serde_json::to_string(transaction) {
+            Ok(json) => println!("{}", json),
+            Err(e) => println!("Aino error: {}", e),
+        }
+    }
 }
 
-#[derive(Deserialize, Debug)]
-struct BatchResponse {
-    batch: String,
+/// The lifecycle state of an agent.
+///
+/// An agent goes from `Running` through `Stopping` to `Stopped`, and a stopped agent can not be
+/// started again. [`start`](fn.start.html) replaces a stopped default agent with a new one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AgentState {
+    /// `Transaction`s are accepted and sent.
+    Running,
+
+    /// The agent is sending the pending `Transaction`s before it stops. New ones are rejected.
+    Stopping,
+
+    /// The agent thread has exited.
+    Stopped,
 }
 
-#[derive(Serialize)]
-struct BatchRequest {
-    transactions: Vec<Transaction>,
+impl AgentState {
+    fn from_u8(state: u8) -> Self {
+        match state {
+            0 => AgentState::Running,
+            1 => AgentState::Stopping,
+            _ => AgentState::Stopped,
+        }
+    }
 }
 
-const MAX_BATCH_SIZE: usize = 500;
+/// A running [`Aino.io`](https://aino.io) agent with its own configuration, queue and agent thread.
+///
+/// The `Agent` is a handle that can be cloned cheaply and shared between threads. Several agents
+/// can run side by side. When the last handle is dropped without stopping the agent, the pending
+/// `Transaction`s are sent in the background before the agent thread exits.
+///
+/// With [`AinoConfig::destinations`](struct.AinoConfig.html#structfield.destinations), a single
+/// agent reports to several Aino.io endpoints or tenants. Every destination has an agent thread
+/// and a queue of its own, and gets a copy of each `Transaction` its [`Route`](enum.Route.html) matches.
+#[derive(Clone)]
+pub struct Agent {
+    inner: Arc<Inner>,
+}
 
-enum ListenResult {
-    Continue,
-    Shutdown,
+struct Inner {
+    state: AtomicU8,
+    /// The destination of `AinoConfig` first, then the named ones.
+    destinations: Vec<Destination>,
 }
 
-struct Agent {
-    sender: mpsc::Sender<Msg>,
-    receiver: Option<mpsc::Receiver<Msg>>,
-    thread_sender: Option<mpsc::Sender<ThreadMsg>>,
-    thread_receiver: mpsc::Receiver<ThreadMsg>,
+/// The agent thread sending to one destination, and the queue in front of it.
+struct Destination {
+    route: Route,
+    sender: UnboundedSender<Msg>,
+    /// Also serializes the callers stopping the agent.
+    thread: Mutex<AgentThread>,
+    /// The task running the agent on the caller's runtime, if it does not have its own thread.
+    task: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    circuit_state: Arc<Mutex<CircuitState>>,
+    queue: Arc<TransactionQueue>,
 }
 
-lazy_static! {
-    static ref AGENT: Mutex<Agent> = {
-        let (sender, receiver) = mpsc::channel();
+/// The messages from an agent thread, and what it reported when it finished.
+struct AgentThread {
+    receiver: mpsc::Receiver<ThreadMsg>,
+    finished: Option<StopOutcome>,
+}
+
+impl Drop for Destination {
+    fn drop(&mut self) {
+        let _ = self.sender.send(Msg::Cancel(None));
+    }
+}
+
+impl Destination {
+    /// Creates the agent thread's `Worker` and hands it to `run`, which returns the task running
+    /// it, if any.
+    fn start<F>(
+        name: Option<String>,
+        config: AinoConfig,
+        run: &mut F,
+    ) -> Result<Destination, AinoError>
+    where
+        F: FnMut(Worker) -> Result<Option<JoinHandle<()>>, Box<dyn std::error::Error>>,
+    {
+        let route = config.route.clone();
+        let (sender, receiver) = unbounded_channel();
         let (thread_sender, thread_receiver) = mpsc::channel();
-        Mutex::new(Agent {
+        let circuit_state = Arc::new(Mutex::new(CircuitState::Closed));
+        let queue = Arc::new(TransactionQueue::new(config.queue.clone()));
+        let task = Worker::new(
+            name,
+            config,
+            receiver,
+            sender.clone(),
+            thread_sender,
+            circuit_state.clone(),
+            queue.clone(),
+        )
+        .and_then(run)
+        .map_err(|err| AinoError::new(format!("Aino.io error: {}", err)))?;
+
+        Ok(Destination {
+            route,
             sender,
-            receiver: Some(receiver),
-            thread_sender: Some(thread_sender),
-            thread_receiver,
+            thread: Mutex::new(AgentThread {
+                receiver: thread_receiver,
+                finished: None,
+            }),
+            task: tokio::sync::Mutex::new(task),
+            circuit_state,
+            queue,
         })
-    };
-}
+    }
 
-/// Starts the [`Aino.io`](https://aino.io) agent. Should only be called once at application startup.
-pub fn start(config: AinoConfig) -> Result<(), AinoError> {
-    let mut agent = AGENT.lock().unwrap();
-    let receiver = agent.receiver.take();
-    let sender = agent.thread_sender.take();
-    match (receiver, sender) {
-        (Some(receiver), Some(sender)) => run(config, receiver, sender)
-            .map_err(|err| AinoError::new(format!("Aino.io error: {}", err))),
-        _ => Err(AinoError::new("Failed to start Aino.io agent".to_string())),
+    /// Hands an admitted `Transaction` over to the agent thread.
+    fn submit(
+        &self,
+        transaction: Transaction,
+        outcome: Option<oneshot::Sender<DeliveryOutcome>>,
+    ) -> Result<(), AinoError> {
+        self.send(Msg::Trx(Box::new(transaction), outcome))
+            .inspect_err(|_| self.queue.unreserve(1))
+    }
+
+    /// Sends the message to the agent thread, which only goes away once the agent has stopped.
+    fn send(&self, msg: Msg) -> Result<(), AinoError> {
+        self.sender.send(msg).map_err(|_| not_running())
     }
 }
 
-/// Adds the [`Transaction`](struct.Transaction.html) to the queue to be sent later.
-pub fn add_transaction(transaction: Transaction) -> Result<(), AinoError> {
-    let sender = {
-        let agent = AGENT.lock().unwrap();
-        agent.sender.clone()
-    };
+impl Agent {
+    /// Starts a new agent with the configuration.
+    pub fn new(config: AinoConfig) -> Result<Agent, AinoError> {
+        Agent::with_runner(config, |worker| {
+            let rt = Runtime::new()?;
+            thread::spawn(move || rt.block_on(worker.run()));
+            Ok(None)
+        })
+    }
 
-    sender
-        .send(Msg::Trx(Box::new(transaction)))
-        .map_err(|e| AinoError::new(format!("Aino error: {}", e)))?;
+    /// Creates the `Worker` of every destination and hands it to `run`, which returns the task
+    /// running it, if any.
+    fn with_runner<F>(config: AinoConfig, mut run: F) -> Result<Agent, AinoError>
+    where
+        F: FnMut(Worker) -> Result<Option<JoinHandle<()>>, Box<dyn std::error::Error>>,
+    {
+        let mut configs: Vec<(Option<String>, AinoConfig)> = config
+            .destinations
+            .iter()
+            .map(|(name, destination)| {
+                (Some(name.clone()), destination.agent_config(name, &config))
+            })
+            .collect();
+        configs.insert(0, (None, config));
 
-    Ok(())
-}
+        let destinations = configs
+            .into_iter()
+            .map(|(name, config)| Destination::start(name, config, &mut run))
+            .collect::<Result<_, _>>()?;
 
-/// Stops the [`Aino.io`](https://aino.io) agent. Adding any new [`Transaction`](struct.Transaction.html)s will result in an error.
-/// This function will wait until all pending [`Transaction`](struct.Transaction.html)s have been sent.
-pub fn stop() -> Result<(), AinoError> {
-    let agent = AGENT.lock().unwrap();
-    match agent.sender.send(Msg::Cancel) {
-        Ok(_) => match agent.thread_receiver.recv() {
-            Ok(msg) => match msg {
-                ThreadMsg::Finished => Ok(()),
-            },
-            Err(e) => Err(AinoError::new(format!("Aino error: {}", e))),
-        },
-        Err(e) => Err(AinoError::new(format!("Aino error: {}", e))),
-    }
-}
-
-fn run(
-    config: AinoConfig,
-    receiver: mpsc::Receiver<Msg>,
-    sender: mpsc::Sender<ThreadMsg>,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut rt = Runtime::new()?;
-
-    thread::spawn(move || {
-        let mut buffer: VecDeque<Transaction> = VecDeque::new();
-        let mut interval_start = Instant::now();
-
-        loop {
-            if let ListenResult::Shutdown = listen_messages(&receiver, &mut buffer) {
-                clear_buffer(Vec::from(buffer), &mut rt, &config);
-
-                sender
-                    .send(ThreadMsg::Finished)
-                    .expect("Failed to send Finished message back to main thread.");
-
-                break;
+        Ok(Agent {
+            inner: Arc::new(Inner {
+                state: AtomicU8::new(AgentState::Running as u8),
+                destinations,
+            }),
+        })
+    }
+
+    /// Returns an [`AgentGuard`](struct.AgentGuard.html) that flushes and stops this agent when it is dropped.
+    pub fn guard(&self) -> AgentGuard {
+        AgentGuard {
+            agent: self.clone(),
+            timeout: GUARD_STOP_TIMEOUT,
+            stopped: false,
+        }
+    }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue to be sent later.
+    /// See [`add_transaction`](fn.add_transaction.html).
+    pub fn add_transaction(&self, transaction: Transaction) -> Result<(), AinoError> {
+        self.enqueue(transaction, None)?;
+        Ok(())
+    }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue and tracks its delivery.
+    /// See [`add_transaction_tracked`](fn.add_transaction_tracked.html).
+    pub fn add_transaction_tracked(
+        &self,
+        transaction: Transaction,
+    ) -> Result<DeliveryHandle, AinoError> {
+        let (outcome, handle) = DeliveryHandle::new();
+        match self.enqueue(transaction, Some(outcome))? {
+            Admission::Accepted => Ok(handle),
+            Admission::Dropped => Ok(DeliveryHandle::resolved(DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::QueueFull,
+            })),
+        }
+    }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue without blocking or locking.
+    /// See [`try_add_transaction`](fn.try_add_transaction.html).
+    ///
+    /// The `Transaction` is only added if every destination it is routed to has room for it. When
+    /// the agent stops while the `Transaction` is being added, the destinations still running get
+    /// it, and the error hands back a copy.
+    pub fn try_add_transaction(&self, transaction: Transaction) -> Result<(), TryAddError> {
+        let transaction = Box::new(transaction);
+        if let Err(error) = self.check_running() {
+            return Err(TryAddError::new(transaction, error));
+        }
+        let routes = self.routes(&transaction);
+        for (i, destination) in routes.iter().enumerate() {
+            if let Err(error) = destination.queue.try_admit() {
+                routes[..i].iter().for_each(|d| d.queue.unreserve(1));
+                return Err(TryAddError::new(transaction, error));
             }
+        }
 
-            if can_send_batch(&interval_start, &config, buffer.len()) {
-                let batch = create_batch_request(&mut buffer);
-                interval_start = Instant::now();
-                rt.spawn(send_batch(config.clone(), batch));
+        // Each destination gets a copy, and the last one the original
+        let Some((last, rest)) = routes.split_last() else {
+            return Ok(());
+        };
+        let mut error = None;
+        for destination in rest {
+            if let Err(e) = destination.submit(Transaction::clone(&transaction), None) {
+                error.get_or_insert(e);
             }
         }
-    });
-    Ok(())
+        if let Some(error) = error {
+            let _ = last.submit(Transaction::clone(&transaction), None);
+            return Err(TryAddError::new(transaction, error));
+        }
+        match last.sender.send(Msg::Trx(transaction, None)) {
+            Ok(()) => Ok(()),
+            Err(SendError(msg)) => {
+                last.queue.unreserve(1);
+                let Msg::Trx(transaction, _) = msg else {
+                    unreachable!("only a transaction was sent")
+                };
+                Err(TryAddError::new(transaction, not_running()))
+            }
+        }
+    }
+
+    /// The destinations the `Transaction` is routed to.
+    fn routes(&self, transaction: &Transaction) -> Vec<&Destination> {
+        self.inner
+            .destinations
+            .iter()
+            .filter(|destination| destination.route.matches(transaction))
+            .collect()
+    }
+
+    fn enqueue(
+        &self,
+        transaction: Transaction,
+        outcome: Option<oneshot::Sender<DeliveryOutcome>>,
+    ) -> Result<Admission, AinoError> {
+        self.check_running()?;
+        let urgent = transaction.is_urgent();
+        let admissions = self
+            .routes(&transaction)
+            .into_iter()
+            .map(|destination| (destination, destination.queue.admit(urgent)))
+            .collect();
+        submit(admissions, transaction, outcome)
+    }
+
+    async fn enqueue_async(
+        &self,
+        transaction: Transaction,
+        outcome: Option<oneshot::Sender<DeliveryOutcome>>,
+    ) -> Result<Admission, AinoError> {
+        self.check_running()?;
+        let urgent = transaction.is_urgent();
+        let mut admissions = Vec::new();
+        for destination in self.routes(&transaction) {
+            admissions.push((destination, destination.queue.admit_async(urgent).await));
+        }
+        submit(admissions, transaction, outcome)
+    }
+
+    /// Returns the lifecycle state of the agent.
+    pub fn state(&self) -> AgentState {
+        AgentState::from_u8(self.inner.state.load(Ordering::SeqCst))
+    }
+
+    fn set_state(&self, state: AgentState) {
+        self.inner.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    fn check_running(&self) -> Result<(), AinoError> {
+        match self.state() {
+            AgentState::Running => Ok(()),
+            _ => Err(not_running()),
+        }
+    }
+
+    /// Sends every `Transaction` added so far and waits until each of them has a final outcome.
+    /// See [`flush`](fn.flush.html).
+    pub fn flush(&self) -> Result<(), AinoError> {
+        futures::executor::block_on(self.flush_async())
+    }
+
+    /// An async variant of [`Agent::flush`](struct.Agent.html#method.flush).
+    pub async fn flush_async(&self) -> Result<(), AinoError> {
+        self.check_running()?;
+        let mut receivers = Vec::with_capacity(self.inner.destinations.len());
+        for destination in &self.inner.destinations {
+            let (done, receiver) = oneshot::channel();
+            destination.send(Msg::Flush(done))?;
+            receivers.push(receiver);
+        }
+
+        for receiver in receivers {
+            receiver.await.map_err(|_| not_running())?;
+        }
+        Ok(())
+    }
+
+    /// Stops the agent after sending all the pending `Transaction`s. See [`stop`](fn.stop.html).
+    ///
+    /// Stopping an agent that has already stopped does nothing.
+    pub fn stop(&self) -> Result<(), AinoError> {
+        let mut threads = self.lock_threads();
+        if self.state() != AgentState::Stopped {
+            self.set_state(AgentState::Stopping);
+            self.cancel(None);
+        }
+        self.collect(&mut threads, |receiver| receiver.recv())?;
+        Ok(())
+    }
+
+    /// Stops the agent, waiting at most `timeout` for the pending `Transaction`s to be sent.
+    /// See [`stop_with_timeout`](fn.stop_with_timeout.html).
+    ///
+    /// Stopping an agent that has already stopped hands back no `Transaction`s.
+    pub fn stop_with_timeout(&self, timeout: Duration) -> Result<StopOutcome, AinoError> {
+        let mut threads = self.lock_threads();
+        let deadline = Instant::now() + timeout;
+        if self.state() != AgentState::Stopped {
+            self.set_state(AgentState::Stopping);
+            self.cancel(Some(deadline));
+        }
+        // The agent thread stops sending at the deadline, and finishes once it has spooled or
+        // handed back the rest, however long that takes
+        self.collect(&mut threads, |receiver| receiver.recv())
+    }
+
+    /// Stops an agent running on the caller's runtime without blocking it.
+    async fn shutdown(&self) -> Result<(), AinoError> {
+        let mut tasks = Vec::with_capacity(self.inner.destinations.len());
+        for destination in &self.inner.destinations {
+            tasks.push(destination.task.lock().await);
+        }
+        if self.state() != AgentState::Stopped {
+            self.set_state(AgentState::Stopping);
+            self.cancel(None);
+            for task in tasks.iter_mut().filter_map(|task| task.take()) {
+                task.await
+                    .map_err(|e| AinoError::new(format!("Aino error: {}", e)))?;
+            }
+        }
+        self.collect(&mut self.lock_threads(), |receiver| receiver.try_recv())?;
+        Ok(())
+    }
+
+    fn lock_threads(&self) -> Vec<MutexGuard<'_, AgentThread>> {
+        self.inner
+            .destinations
+            .iter()
+            .map(|destination| destination.thread.lock().unwrap())
+            .collect()
+    }
+
+    /// Asks every agent thread to send the pending `Transaction`s and exit. The agent threads
+    /// may already have finished after an earlier stop timed out.
+    fn cancel(&self, deadline: Option<Instant>) {
+        for destination in &self.inner.destinations {
+            let _ = destination.send(Msg::Cancel(deadline));
+        }
+    }
+
+    /// Waits for the agent threads that have not finished yet with `receive`, and marks the agent
+    /// stopped once all of them have. Hands back the `Transaction`s not handed back before.
+    fn collect<F, E>(
+        &self,
+        threads: &mut [MutexGuard<'_, AgentThread>],
+        mut receive: F,
+    ) -> Result<StopOutcome, AinoError>
+    where
+        F: FnMut(&mpsc::Receiver<ThreadMsg>) -> Result<ThreadMsg, E>,
+        E: fmt::Display,
+    {
+        for thread in threads
+            .iter_mut()
+            .filter(|thread| thread.finished.is_none())
+        {
+            let ThreadMsg::Finished(outcome) = receive(&thread.receiver)
+                .map_err(|e| AinoError::new(format!("Aino error: {}", e)))?;
+            thread.finished = Some(outcome);
+        }
+        self.set_state(AgentState::Stopped);
+
+        let mut outcome = StopOutcome {
+            delivered: 0,
+            spooled: 0,
+            undelivered: Vec::new(),
+        };
+        for finished in threads
+            .iter_mut()
+            .filter_map(|thread| thread.finished.as_mut())
+        {
+            outcome.delivered += finished.delivered;
+            outcome.spooled += finished.spooled;
+            outcome.undelivered.append(&mut finished.undelivered);
+        }
+        Ok(outcome)
+    }
+
+    /// Returns the state of the circuit breaker around the Data API. With several destinations,
+    /// it is the state of the least reachable one.
+    pub fn circuit_state(&self) -> CircuitState {
+        self.inner
+            .destinations
+            .iter()
+            .map(|destination| *destination.circuit_state.lock().unwrap())
+            .max_by_key(|state| match state {
+                CircuitState::Closed => 0,
+                CircuitState::HalfOpen => 1,
+                CircuitState::Open => 2,
+            })
+            .unwrap_or(CircuitState::Closed)
+    }
+
+    /// Returns the number of `Transaction`s dropped because the queue was full. A `Transaction`
+    /// dropped by several destinations is counted once for each of them.
+    pub fn dropped_transactions(&self) -> u64 {
+        self.inner
+            .destinations
+            .iter()
+            .map(|destination| destination.queue.dropped())
+            .sum()
+    }
 }
 
-fn clear_buffer(buffer: Vec<Transaction>, rt: &mut Runtime, config: &AinoConfig) {
-    // Create a list of tasks that will send the rest of the batches in the buffer
-    let handles: Vec<JoinHandle<()>> = buffer
-        .as_slice()
-        .chunks(MAX_BATCH_SIZE)
-        .map(|b| {
-            let batch = BatchRequest {
-                transactions: b.to_vec(),
-            };
-            rt.spawn(send_batch(config.clone(), batch))
-        })
-        .collect();
+/// Hands the `Transaction` over to the destinations that admitted it. Its delivery is tracked by
+/// the first destination it is routed to, whose admission is returned. An error from one
+/// destination does not keep the `Transaction` from the others, and the first one is returned.
+fn submit(
+    admissions: Vec<(&Destination, Result<Admission, AinoError>)>,
+    transaction: Transaction,
+    mut outcome: Option<oneshot::Sender<DeliveryOutcome>>,
+) -> Result<Admission, AinoError> {
+    let mut first = None;
+    let mut error = None;
+    let mut accepted = Vec::with_capacity(admissions.len());
+    for (i, (destination, admission)) in admissions.into_iter().enumerate() {
+        match admission {
+            Ok(admission) => {
+                if admission == Admission::Accepted {
+                    let outcome = if i == 0 { outcome.take() } else { None };
+                    accepted.push((destination, outcome));
+                }
+                first.get_or_insert(admission);
+            }
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    // Each destination gets a copy, and the last one the original
+    if let Some((last, last_outcome)) = accepted.pop() {
+        for (destination, outcome) in accepted {
+            if let Err(e) = destination.submit(transaction.clone(), outcome) {
+                error.get_or_insert(e);
+            }
+        }
+        if let Err(e) = last.submit(transaction, last_outcome) {
+            error.get_or_insert(e);
+        }
+    }
 
-    // Wait for all to be sent
-    rt.block_on(async { join_all(handles).await });
+    match error {
+        Some(e) => Err(e),
+        None => Ok(first.unwrap_or_else(|| {
+            // A `Transaction` that matches no route is not sent anywhere
+            if let Some(outcome) = outcome {
+                let _ = outcome.send(DeliveryOutcome::Dropped {
+                    reason: AinoErrorKind::Other,
+                });
+            }
+            Admission::Accepted
+        })),
+    }
 }
 
-fn can_send_batch(interval_start: &Instant, config: &AinoConfig, buffer_len: usize) -> bool {
-    buffer_len > 0
-        && (interval_start.elapsed().as_millis() >= config.send_interval as u128
-            || MAX_BATCH_SIZE < buffer_len)
+/// An [`Agent`](struct.Agent.html) that runs on an existing Tokio runtime instead of its own thread
+/// and runtime, with an async API.
+///
+/// The runtime must have the time driver enabled. Call [`shutdown`](#method.shutdown) before the
+/// runtime is dropped, as the pending `Transaction`s are lost with it.
+#[derive(Clone)]
+pub struct AsyncAgent {
+    agent: Agent,
 }
 
-fn listen_messages(
-    receiver: &mpsc::Receiver<Msg>,
-    buffer: &mut VecDeque<Transaction>,
-) -> ListenResult {
-    match receiver.try_recv() {
-        Ok(msg) => match msg {
-            Msg::Cancel => ListenResult::Shutdown,
-            Msg::Trx(transaction) => {
-                buffer.push_back(*transaction);
-                ListenResult::Continue
-            }
-        },
-        Err(e) => match e {
-            mpsc::TryRecvError::Empty => ListenResult::Continue,
-            mpsc::TryRecvError::Disconnected => ListenResult::Shutdown,
-        },
+impl AsyncAgent {
+    /// Starts a new agent with the configuration as a task on the runtime.
+    pub fn spawn(config: AinoConfig, handle: &Handle) -> Result<AsyncAgent, AinoError> {
+        let agent = Agent::with_runner(config, |worker| Ok(Some(handle.spawn(worker.run()))))?;
+        Ok(AsyncAgent { agent })
+    }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue to be sent later. With
+    /// [`OverflowPolicy::Block`](enum.OverflowPolicy.html#variant.Block), waits for room
+    /// without blocking the thread.
+    pub async fn add_transaction(&self, transaction: Transaction) -> Result<(), AinoError> {
+        self.agent.enqueue_async(transaction, None).await?;
+        Ok(())
     }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue and returns a
+    /// [`DeliveryHandle`](struct.DeliveryHandle.html) that resolves once it has a final outcome.
+    pub async fn add_transaction_tracked(
+        &self,
+        transaction: Transaction,
+    ) -> Result<DeliveryHandle, AinoError> {
+        let (outcome, handle) = DeliveryHandle::new();
+        match self.agent.enqueue_async(transaction, Some(outcome)).await? {
+            Admission::Accepted => Ok(handle),
+            Admission::Dropped => Ok(DeliveryHandle::resolved(DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::QueueFull,
+            })),
+        }
+    }
+
+    /// Adds the [`Transaction`](struct.Transaction.html) to the queue without waiting.
+    /// See [`try_add_transaction`](fn.try_add_transaction.html).
+    pub fn try_add_transaction(&self, transaction: Transaction) -> Result<(), TryAddError> {
+        self.agent.try_add_transaction(transaction)
+    }
+
+    /// Sends every `Transaction` added so far and waits until each of them has a final outcome.
+    pub async fn flush(&self) -> Result<(), AinoError> {
+        self.agent.flush_async().await
+    }
+
+    /// Stops the agent after sending all the pending `Transaction`s, including the batches still
+    /// waiting to be resent. Shutting down an agent that has already stopped does nothing.
+    pub async fn shutdown(&self) -> Result<(), AinoError> {
+        self.agent.shutdown().await
+    }
+
+    /// Returns the lifecycle state of the agent.
+    pub fn state(&self) -> AgentState {
+        self.agent.state()
+    }
+
+    /// Returns the state of the circuit breaker around the Data API.
+    pub fn circuit_state(&self) -> CircuitState {
+        self.agent.circuit_state()
+    }
+
+    /// Returns the number of `Transaction`s dropped because the queue was full.
+    pub fn dropped_transactions(&self) -> u64 {
+        self.agent.dropped_transactions()
+    }
+}
+
+fn not_running() -> AinoError {
+    AinoError::with_kind(
+        AinoErrorKind::NotRunning,
+        "Aino error: The agent is not running".to_string(),
+    )
+}
+
+lazy_static! {
+    /// The agent used by the free functions.
+    static ref AGENT: RwLock<Option<Agent>> = RwLock::new(None);
 }
 
-fn create_batch_request(buffer: &mut VecDeque<Transaction>) -> BatchRequest {
-    let batch_size = min(MAX_BATCH_SIZE, buffer.len());
-    BatchRequest {
-        transactions: buffer.drain(..batch_size).collect(),
+/// The agent started with [`start`](fn.start.html).
+fn default_agent() -> Result<Agent, AinoError> {
+    match &*AGENT.read().unwrap() {
+        Some(agent) => Ok(agent.clone()),
+        None => Err(AinoError::with_kind(
+            AinoErrorKind::NotRunning,
+            "Aino error: The agent has not been started".to_string(),
+        )),
     }
 }
 
-async fn send_batch(config: AinoConfig, batch: BatchRequest) {
-    if let Ok(req) = surf::post(&config.url)
-        .header("Authorization", format!("apikey {}", &config.api_key))
-        .body_json(&batch)
+/// Starts the [`Aino.io`](https://aino.io) agent, usually at application startup.
+///
+/// Once the agent has been stopped, it can be started again, possibly with a new configuration.
+/// Starting an agent that is still running or stopping fails with
+/// [`AinoErrorKind::AlreadyRunning`](enum.AinoErrorKind.html#variant.AlreadyRunning).
+///
+/// The free functions of this crate use the agent started here. To run several agents, or to pass
+/// one around explicitly, create an [`Agent`](struct.Agent.html) instead.
+pub fn start(config: AinoConfig) -> Result<(), AinoError> {
+    let mut agent = AGENT.write().unwrap();
+    if agent
+        .as_ref()
+        .is_some_and(|agent| agent.state() != AgentState::Stopped)
     {
-        // TODO: Implement resending if sending fails
-        if let Err(e) = req.await {
-            println!("Aino error: {}", e);
-        }
-    };
+        return Err(AinoError::with_kind(
+            AinoErrorKind::AlreadyRunning,
+            "Aino error: The agent is already running".to_string(),
+        ));
+    }
+    *agent = Some(Agent::new(config)?);
+    Ok(())
+}
+
+/// Starts the agent like [`start`](fn.start.html), and returns an [`AgentGuard`](struct.AgentGuard.html)
+/// that flushes and stops the agent when it is dropped.
+pub fn start_with_guard(config: AinoConfig) -> Result<AgentGuard, AinoError> {
+    start(config)?;
+    Ok(default_agent()?.guard())
+}
+
+/// Adds the [`Transaction`](struct.Transaction.html) to the queue to be sent later.
+///
+/// When the queue is full, the configured [`OverflowPolicy`](enum.OverflowPolicy.html) decides
+/// whether the `Transaction` is dropped, the call blocks, or an error is returned.
+pub fn add_transaction(transaction: Transaction) -> Result<(), AinoError> {
+    default_agent()?.add_transaction(transaction)
+}
+
+/// Adds the [`Transaction`](struct.Transaction.html) to the queue without ever blocking or waiting
+/// for a lock, which suits latency-critical paths.
+///
+/// The overflow policy is not applied. When the agent is not running or the queue is full, the
+/// `Transaction` is handed back in the [`TryAddError`](struct.TryAddError.html), so that the caller
+/// can decide whether to drop or log it.
+pub fn try_add_transaction(transaction: Transaction) -> Result<(), TryAddError> {
+    // Only `start` takes the write lock, so failing to read means the agent is being started
+    match AGENT.try_read().as_deref() {
+        Ok(Some(agent)) => agent.try_add_transaction(transaction),
+        _ => Err(TryAddError::new(Box::new(transaction), not_running())),
+    }
+}
+
+/// Adds the [`Transaction`](struct.Transaction.html) to the queue like
+/// [`add_transaction`](fn.add_transaction.html), and returns a [`DeliveryHandle`](struct.DeliveryHandle.html)
+/// that resolves once the `Transaction` has been delivered, rejected or dropped.
+pub fn add_transaction_tracked(transaction: Transaction) -> Result<DeliveryHandle, AinoError> {
+    default_agent()?.add_transaction_tracked(transaction)
+}
+
+/// Sends every [`Transaction`](struct.Transaction.html) added so far without waiting for `send_interval`,
+/// and waits until each of them has been delivered, rejected, stored in the spool or given up on. The
+/// agent keeps running.
+///
+/// Batches waiting to be resent are still sent according to the retry backoff.
+pub fn flush() -> Result<(), AinoError> {
+    futures::executor::block_on(flush_async())
+}
+
+/// An async variant of [`flush`](fn.flush.html).
+pub async fn flush_async() -> Result<(), AinoError> {
+    let agent = default_agent()?;
+    agent.flush_async().await
+}
+
+/// Stops the [`Aino.io`](https://aino.io) agent. Adding any new [`Transaction`](struct.Transaction.html)s will result in an error.
+/// This function will wait until all pending [`Transaction`](struct.Transaction.html)s have been sent,
+/// including the batches still waiting to be resent.
+///
+/// Stopping an agent that has already stopped does nothing, but stopping one that was never
+/// started fails with [`AinoErrorKind::NotRunning`](enum.AinoErrorKind.html#variant.NotRunning).
+pub fn stop() -> Result<(), AinoError> {
+    default_agent()?.stop()
+}
+
+/// Stops the [`Aino.io`](https://aino.io) agent like [`stop`](fn.stop.html), but waits at most `timeout`
+/// for the pending [`Transaction`](struct.Transaction.html)s to be sent.
+///
+/// The `Transaction`s that were not delivered by then are stored in the spool, if one is configured,
+/// to be sent on the next start. The ones that could not be stored are handed back in the
+/// [`StopOutcome`](struct.StopOutcome.html), so that they can be persisted or logged before exiting.
+/// Storing them may take a while after the `timeout`, and this function waits for it to finish.
+pub fn stop_with_timeout(timeout: Duration) -> Result<StopOutcome, AinoError> {
+    default_agent()?.stop_with_timeout(timeout)
+}
+
+/// Returns the lifecycle state of the agent. It is [`Stopped`](enum.AgentState.html#variant.Stopped)
+/// before the agent has been started.
+pub fn state() -> AgentState {
+    default_agent()
+        .map(|agent| agent.state())
+        .unwrap_or(AgentState::Stopped)
+}
+
+/// Returns the state of the circuit breaker around the Data API. The circuit is
+/// [`Open`](enum.CircuitState.html#variant.Open) while the Data API is unreachable.
+pub fn circuit_state() -> CircuitState {
+    default_agent()
+        .map(|agent| agent.circuit_state())
+        .unwrap_or(CircuitState::Closed)
+}
+
+/// Returns the total number of [`Transaction`](struct.Transaction.html)s dropped because the queue was full.
+pub fn dropped_transactions() -> u64 {
+    default_agent()
+        .map(|agent| agent.dropped_transactions())
+        .unwrap_or_default()
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::Status;
-    use std::iter::repeat_with;
-    use std::time::{Duration, SystemTime};
+    use crate::spool::Spool;
+    use crate::{DeadLetterHandler, DestinationConfig, RoutePredicate, SpoolConfig, Status};
+    use std::io::{Read, Write};
 
-    fn create_config(send_interval: u32) -> AinoConfig {
+    /// A configuration pointing to a closed port, so nothing is ever delivered.
+    fn create_config() -> AinoConfig {
         AinoConfig {
-            send_interval,
-            url: "".to_string(),
+            url: "http://127.0.0.1:9/".to_string(),
+            send_interval: 60_000,
+            ..AinoConfig::default()
+        }
+    }
+
+    fn create_destination(route: Route) -> DestinationConfig {
+        DestinationConfig {
+            url: "http://127.0.0.1:9/".to_string(),
             api_key: "".to_string(),
+            send_interval: None,
+            max_batch_size: None,
+            max_batch_bytes: None,
+            route,
         }
     }
 
     fn create_trx() -> Transaction {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap();
         Transaction::new(
             "from".to_string(),
             "to".to_string(),
             "operation".to_string(),
             Status::Success,
-            timestamp.as_millis(),
+            1,
             "flow_id".to_string(),
             "integration_segment".to_string(),
         )
     }
 
     #[test]
-    fn test_can_send_batch_empty_buffer() {
-        let config = create_config(10);
-        let interval_start = Instant::now();
-        let buffer: VecDeque<Transaction> = VecDeque::new();
+    fn test_agents_are_independent() {
+        let first = Agent::new(create_config()).unwrap();
+        let second = Agent::new(create_config()).unwrap();
+        first.add_transaction(create_trx()).unwrap();
+        first.clone().add_transaction(create_trx()).unwrap();
+        second.add_transaction(create_trx()).unwrap();
+
+        let timeout = Duration::from_millis(100);
+        assert_eq!(
+            first.stop_with_timeout(timeout).unwrap().undelivered.len(),
+            2
+        );
         assert_eq!(
-            can_send_batch(&interval_start, &config, buffer.len()),
-            false
+            second.stop_with_timeout(timeout).unwrap().undelivered.len(),
+            1
         );
     }
 
     #[test]
-    fn test_can_send_batch_full_buffer() {
-        let config = create_config(10);
-        let interval_start = Instant::now();
-        let buffer: VecDeque<Transaction> = repeat_with(|| create_trx())
-            .take(MAX_BATCH_SIZE + 1)
-            .collect();
-        assert_eq!(can_send_batch(&interval_start, &config, buffer.len()), true);
+    fn test_guard_stops_agent() {
+        let agent = Agent::new(create_config()).unwrap();
+        let guard = agent.guard().with_stop_timeout(Duration::from_millis(100));
+        agent.add_transaction(create_trx()).unwrap();
+        assert_eq!(guard.stop().unwrap().undelivered.len(), 1);
     }
 
     #[test]
-    fn test_can_send_batch_timer() {
-        let config = create_config(10);
-        let interval_start = Instant::now();
-        let buffer: VecDeque<Transaction> = repeat_with(|| create_trx())
-            .take(MAX_BATCH_SIZE - 1)
-            .collect();
-        thread::sleep(Duration::from_millis(11));
-        assert_eq!(can_send_batch(&interval_start, &config, buffer.len()), true);
+    fn test_dropped_guard_spools_undelivered() {
+        let directory =
+            std::env::temp_dir().join(format!("aino-agent-guard-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let spool_config = SpoolConfig {
+            directory: directory.clone(),
+            max_size: 1024 * 1024,
+            max_age: 60,
+        };
+        let mut config = create_config();
+        config.spool = Some(spool_config.clone());
+        let agent = Agent::new(config).unwrap();
+        let guard = agent.guard().with_stop_timeout(Duration::from_millis(100));
+        for _ in 0..3 {
+            agent.add_transaction(create_trx()).unwrap();
+        }
+        drop(guard);
+        assert_eq!(agent.state(), AgentState::Stopped);
+
+        let spool = Spool::open(spool_config).unwrap();
+        let files = spool.files().unwrap();
+        let spooled = files.iter().filter_map(|path| spool.load(path));
+        assert_eq!(
+            spooled.map(|batch| batch.transactions.len()).sum::<usize>(),
+            3
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 
     #[test]
-    fn test_can_send_batch_timer_with_empty_buffer() {
-        let config = create_config(10);
-        let interval_start = Instant::now();
-        let buffer: VecDeque<Transaction> = VecDeque::new();
-        thread::sleep(Duration::from_millis(11));
+    fn test_timed_out_send_is_reported() {
+        // Accepts the connection but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut config = AinoConfig {
+            url: format!("http://{}/", listener.local_addr().unwrap()),
+            request_timeout: 100,
+            ..create_config()
+        };
+        config.retry.max_attempts = 1;
+        let agent = Agent::new(config).unwrap();
+
+        let handle = agent.add_transaction_tracked(create_trx()).unwrap();
+        agent.flush().unwrap();
         assert_eq!(
-            can_send_batch(&interval_start, &config, buffer.len()),
-            false
+            handle.wait(),
+            DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::Timeout
+            }
         );
+        agent.stop_with_timeout(Duration::from_millis(100)).unwrap();
+    }
+
+    #[test]
+    fn test_stop_waits_for_busy_agent_thread() {
+        // Rejects the first batch, so that the agent thread is busy in the dead letter handler
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut chunk = [0; 1024];
+            while !request.ends_with(b"]}") {
+                let read = stream.read(&mut chunk).unwrap();
+                request.extend_from_slice(&chunk[..read]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+        });
+        let (busy, is_busy) = mpsc::channel();
+        let busy = Mutex::new(busy);
+        let config = AinoConfig {
+            url,
+            send_interval: 10,
+            dead_letter_handler: Some(DeadLetterHandler::new(move |_| {
+                busy.lock().unwrap().send(()).unwrap();
+                thread::sleep(Duration::from_millis(300));
+            })),
+            ..create_config()
+        };
+        let agent = Agent::new(config).unwrap();
+
+        agent.add_transaction(create_trx()).unwrap();
+        is_busy.recv_timeout(Duration::from_secs(10)).unwrap();
+        let trx = create_trx();
+        agent.add_transaction(trx.clone()).unwrap();
+        let outcome = agent.stop_with_timeout(Duration::from_millis(10)).unwrap();
+        assert_eq!(outcome.undelivered.len(), 1);
+        assert_eq!(outcome.undelivered[0].id, trx.id);
+        assert_eq!(agent.state(), AgentState::Stopped);
     }
 
     #[test]
-    fn test_create_batch_with_zero_transactions() {
-        let mut buffer: VecDeque<Transaction> = VecDeque::new();
-        assert_eq!(create_batch_request(&mut buffer).transactions.len(), 0);
+    fn test_stop_twice() {
+        let agent = Agent::new(create_config()).unwrap();
+        assert_eq!(agent.state(), AgentState::Running);
+        agent.stop().unwrap();
+        assert_eq!(agent.state(), AgentState::Stopped);
+        agent.stop().unwrap();
+        let outcome = agent.stop_with_timeout(Duration::ZERO).unwrap();
+        assert!(outcome.undelivered.is_empty());
+
+        let error = agent.add_transaction(create_trx()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::NotRunning);
+        assert_eq!(agent.flush().unwrap_err().kind(), AinoErrorKind::NotRunning);
     }
 
     #[test]
-    fn test_create_batch_with_less_than_max_transactions() {
-        let mut buffer: VecDeque<Transaction> = repeat_with(|| create_trx())
-            .take(MAX_BATCH_SIZE - 1)
-            .collect();
+    fn test_restart_default_agent() {
+        assert_eq!(stop().unwrap_err().kind(), AinoErrorKind::NotRunning);
+        start(create_config()).unwrap();
+        assert_eq!(state(), AgentState::Running);
+        let error = start(create_config()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::AlreadyRunning);
+
+        stop().unwrap();
+        stop().unwrap();
+        assert_eq!(state(), AgentState::Stopped);
+        assert!(add_transaction(create_trx()).is_err());
+
+        let mut config = create_config();
+        config.send_interval = 10;
+        start(config).unwrap();
+        add_transaction(create_trx()).unwrap();
         assert_eq!(
-            create_batch_request(&mut buffer).transactions.len(),
-            MAX_BATCH_SIZE - 1
+            stop_with_timeout(Duration::from_millis(100))
+                .unwrap()
+                .undelivered
+                .len(),
+            1
         );
-        assert_eq!(buffer.len(), 0);
     }
 
     #[test]
-    fn test_create_batch_with_more_than_max_transactions() {
-        let mut buffer: VecDeque<Transaction> = repeat_with(|| create_trx())
-            .take(MAX_BATCH_SIZE + 1)
-            .collect();
+    fn test_async_agent_on_existing_runtime() {
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut config = create_config();
+        config.retry.max_attempts = 1;
+        let agent = AsyncAgent::spawn(config, rt.handle()).unwrap();
+
+        rt.block_on(async {
+            agent.add_transaction(create_trx()).await.unwrap();
+            let handle = agent.add_transaction_tracked(create_trx()).await.unwrap();
+            agent.flush().await.unwrap();
+            assert!(matches!(handle.await, DeliveryOutcome::Dropped { .. }));
+
+            agent.shutdown().await.unwrap();
+            assert_eq!(agent.state(), AgentState::Stopped);
+            agent.shutdown().await.unwrap();
+            let error = agent.add_transaction(create_trx()).await.unwrap_err();
+            assert_eq!(error.kind(), AinoErrorKind::NotRunning);
+        });
+    }
+
+    #[test]
+    fn test_try_add_transaction_hands_back() {
+        let mut config = create_config();
+        config.queue.max_size = 1;
+        let agent = Agent::new(config).unwrap();
+        agent.try_add_transaction(create_trx()).unwrap();
+
+        let trx = create_trx();
+        let error = agent.try_add_transaction(trx.clone()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::QueueFull);
+        assert_eq!(error.into_transaction().id, trx.id);
+        assert_eq!(agent.dropped_transactions(), 0);
+
+        agent.stop_with_timeout(Duration::from_millis(100)).unwrap();
+        let error = agent.try_add_transaction(trx.clone()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::NotRunning);
+        assert_eq!(error.transaction().id, trx.id);
+    }
+
+    #[test]
+    fn test_try_add_transaction_while_a_destination_stops() {
+        let mut config = create_config();
+        config.queue.max_size = 1;
+        config
+            .destinations
+            .insert("tenant".to_string(), create_destination(Route::All));
+        // The agent thread of the top-level destination is gone, as if it had already stopped
+        let mut started = 0;
+        let agent = Agent::with_runner(config, |worker| {
+            started += 1;
+            if started > 1 {
+                let rt = Runtime::new()?;
+                thread::spawn(move || rt.block_on(worker.run()));
+            }
+            Ok(None)
+        })
+        .unwrap();
+
+        let trx = create_trx();
+        let error = agent.try_add_transaction(trx.clone()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::NotRunning);
+        assert_eq!(error.transaction().id, trx.id);
+
+        // The room was given back where the `Transaction` was not added, and the tenant has it
+        assert!(agent.inner.destinations[0].queue.try_admit().is_ok());
+        assert!(agent.inner.destinations[1].queue.try_admit().is_err());
+    }
+
+    #[test]
+    fn test_fan_out_to_destinations() {
+        let mut config = create_config();
+        config.route = Route::IntegrationSegment(vec!["orders".to_string()]);
+        config
+            .destinations
+            .insert("tenant".to_string(), create_destination(Route::All));
+        let audit = RoutePredicate::new(|trx| trx.to == "audit");
+        config.destinations.insert(
+            "audit".to_string(),
+            create_destination(Route::Custom(audit)),
+        );
+        let agent = Agent::new(config).unwrap();
+
+        agent.add_transaction(create_trx()).unwrap();
+        let mut trx = create_trx();
+        trx.integration_segment = "orders".to_string();
+        trx.to = "audit".to_string();
+        let handle = agent.add_transaction_tracked(trx.clone()).unwrap();
+
+        let outcome = agent.stop_with_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(outcome.undelivered.len(), 4);
+        let copies = outcome.undelivered.iter().filter(|t| t.id == trx.id);
+        assert_eq!(copies.count(), 3);
         assert_eq!(
-            create_batch_request(&mut buffer).transactions.len(),
-            MAX_BATCH_SIZE
+            futures::executor::block_on(handle),
+            DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::NotRunning
+            }
         );
-        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_try_add_transaction_to_every_destination_or_none() {
+        let mut config = create_config();
+        config.queue.max_size = 1;
+        config.route = Route::To(vec!["audit".to_string()]);
+        config.destinations.insert(
+            "tenant".to_string(),
+            create_destination(Route::From(vec!["from".to_string()])),
+        );
+        let agent = Agent::new(config).unwrap();
+        agent.try_add_transaction(create_trx()).unwrap();
+
+        let mut trx = create_trx();
+        trx.to = "audit".to_string();
+        let error = agent.try_add_transaction(trx.clone()).unwrap_err();
+        assert_eq!(error.kind(), AinoErrorKind::QueueFull);
+
+        // The room taken from the first destination was given back
+        trx.from = "other".to_string();
+        agent.try_add_transaction(trx).unwrap();
+        let outcome = agent.stop_with_timeout(Duration::from_millis(100)).unwrap();
+        assert_eq!(outcome.undelivered.len(), 2);
     }
 }
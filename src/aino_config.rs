@@ -1,9 +1,17 @@
-use crate::AinoError;
+use crate::{
+    AinoError, CircuitBreakerConfig, DeadLetterConfig, DeadLetterHandler, DeliveryOrder,
+    DestinationConfig, QueueConfig, ReceiptHandler, RetryConfig, Route, SpoolConfig,
+};
 use config::{Config, Environment, File, FileFormat};
+use std::collections::BTreeMap;
 use std::env;
 
 /// The configuration needed for the [`Aino.io`](https://aino.io) agent.
+///
+/// Usually read with [`AinoConfig::new`](#method.new). In code, start from
+/// [`AinoConfig::default`](#impl-Default-for-AinoConfig) and set the fields, as new ones may be added.
 #[derive(Deserialize, Debug, Clone)]
+#[non_exhaustive]
 pub struct AinoConfig {
     /// `Aino.io` API URL. Should be https://data.aino.io/rest/v2/transaction.
     pub url: String,
@@ -15,6 +23,124 @@ pub struct AinoConfig {
     /// The interval for the agent to send a batch of [`Transaction`](struct.Transaction.html)s.
     #[serde(alias = "sendInterval")]
     pub send_interval: u32,
+
+    /// The maximum number of [`Transaction`](struct.Transaction.html)s in a single batch. Must be at least 1.
+    #[serde(default = "default_max_batch_size", alias = "maxBatchSize")]
+    pub max_batch_size: usize,
+
+    /// The maximum size of a single batch, in bytes. A batch is sent as soon as either limit is reached.
+    #[serde(default = "default_max_batch_bytes", alias = "maxBatchBytes")]
+    pub max_batch_bytes: usize,
+
+    /// The maximum number of batches being sent at the same time. When it is reached, the
+    /// `Transaction`s are buffered until one of the sends finishes.
+    #[serde(default = "default_max_in_flight", alias = "maxInFlight")]
+    pub max_in_flight: usize,
+
+    /// How long to wait for the connection to the Data API to be established, in milliseconds.
+    #[serde(default = "default_connect_timeout", alias = "connectTimeout")]
+    pub connect_timeout: u32,
+
+    /// How long a single batch request to the Data API may take in total, in milliseconds.
+    /// A request that times out is resent like any other failed request.
+    #[serde(default = "default_request_timeout", alias = "requestTimeout")]
+    pub request_timeout: u32,
+
+    /// The order in which the `Transaction`s are delivered.
+    #[serde(default)]
+    pub ordering: DeliveryOrder,
+
+    /// The [`Transaction`](struct.Transaction.html)s sent to `url`. Defaults to all of them.
+    #[serde(default)]
+    pub route: Route,
+
+    /// Additional named destinations, each with its own URL, API key, batching and route.
+    /// Each destination holds its own queue of up to `queue.max_size` `Transaction`s.
+    #[serde(default)]
+    pub destinations: BTreeMap<String, DestinationConfig>,
+
+    /// The limit for the `Transaction`s held in memory, and what to do when it is reached.
+    #[serde(default)]
+    pub queue: QueueConfig,
+
+    /// The policy for resending batches that failed to be sent.
+    #[serde(default)]
+    pub retry: RetryConfig,
+
+    /// The circuit breaker that stops sending while the Data API is unreachable.
+    #[serde(default, alias = "circuitBreaker")]
+    pub circuit_breaker: CircuitBreakerConfig,
+
+    /// The on-disk spool for the batches that could not be delivered (optional).
+    #[serde(default)]
+    pub spool: Option<SpoolConfig>,
+
+    /// The file for the batches the Data API permanently rejected (optional).
+    #[serde(default, alias = "deadLetter")]
+    pub dead_letter: Option<DeadLetterConfig>,
+
+    /// A function called with every batch the Data API permanently rejected (optional).
+    /// Can only be set in code.
+    #[serde(skip)]
+    pub dead_letter_handler: Option<DeadLetterHandler>,
+
+    /// A function called with a receipt for every batch the Data API accepted (optional).
+    /// Can only be set in code.
+    #[serde(skip)]
+    pub receipt_handler: Option<ReceiptHandler>,
+}
+
+pub(crate) const SEND_INTERVAL: u32 = 1_000;
+pub(crate) const MAX_BATCH_SIZE: usize = 500;
+pub(crate) const MAX_BATCH_BYTES: usize = 1024 * 1024;
+pub(crate) const MAX_IN_FLIGHT: usize = 4;
+pub(crate) const CONNECT_TIMEOUT: u32 = 10_000;
+pub(crate) const REQUEST_TIMEOUT: u32 = 30_000;
+
+fn default_max_batch_size() -> usize {
+    MAX_BATCH_SIZE
+}
+
+fn default_max_batch_bytes() -> usize {
+    MAX_BATCH_BYTES
+}
+
+fn default_max_in_flight() -> usize {
+    MAX_IN_FLIGHT
+}
+
+fn default_connect_timeout() -> u32 {
+    CONNECT_TIMEOUT
+}
+
+fn default_request_timeout() -> u32 {
+    REQUEST_TIMEOUT
+}
+
+impl Default for AinoConfig {
+    /// A configuration with an empty `url` and `api_key`, and the defaults for everything else.
+    fn default() -> Self {
+        AinoConfig {
+            url: String::new(),
+            api_key: String::new(),
+            send_interval: SEND_INTERVAL,
+            max_batch_size: MAX_BATCH_SIZE,
+            max_batch_bytes: MAX_BATCH_BYTES,
+            max_in_flight: MAX_IN_FLIGHT,
+            connect_timeout: CONNECT_TIMEOUT,
+            request_timeout: REQUEST_TIMEOUT,
+            ordering: DeliveryOrder::default(),
+            route: Route::default(),
+            destinations: BTreeMap::new(),
+            queue: QueueConfig::default(),
+            retry: RetryConfig::default(),
+            circuit_breaker: CircuitBreakerConfig::default(),
+            spool: None,
+            dead_letter: None,
+            dead_letter_handler: None,
+            receipt_handler: None,
+        }
+    }
 }
 
 impl AinoConfig {
@@ -30,8 +156,91 @@ impl AinoConfig {
             .build()
             .map_err(|err| AinoError::new(err.to_string()))?;
 
-        config
+        let config: AinoConfig = config
+            .try_deserialize()
+            .map_err(|err| AinoError::new(err.to_string()))?;
+        config.check()?;
+        Ok(config)
+    }
+
+    /// Rejects the settings the agent cannot work with, such as an empty batch.
+    fn check(&self) -> Result<(), AinoError> {
+        let batch_sizes =
+            std::iter::once(("max_batch_size".to_string(), Some(self.max_batch_size))).chain(
+                self.destinations.iter().map(|(name, destination)| {
+                    (
+                        format!("destinations.{}.max_batch_size", name),
+                        destination.max_batch_size,
+                    )
+                }),
+            );
+        for (key, max_batch_size) in batch_sizes {
+            if max_batch_size == Some(0) {
+                return Err(AinoError::new(format!(
+                    "Aino error: {} must be at least 1",
+                    key
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_config(toml: &str) -> AinoConfig {
+        Config::builder()
+            .add_source(File::from_str(toml, FileFormat::Toml))
+            .build()
+            .unwrap()
             .try_deserialize()
-            .map_err(|err| AinoError::new(err.to_string()))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_check_rejects_zero_max_batch_size() {
+        let config = read_config(
+            r#"
+            url = "http://default"
+            api_key = "default"
+            send_interval = 1000
+            max_batch_size = 0
+            "#,
+        );
+        assert!(config.check().is_err());
+    }
+
+    #[test]
+    fn test_check_rejects_zero_destination_max_batch_size() {
+        let config = read_config(
+            r#"
+            url = "http://default"
+            api_key = "default"
+            send_interval = 1000
+
+            [destinations.tenant]
+            url = "http://tenant"
+            api_key = "tenant"
+            max_batch_size = 0
+            "#,
+        );
+        let error = config.check().unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("destinations.tenant.max_batch_size"));
+    }
+
+    #[test]
+    fn test_check_accepts_defaults() {
+        let config = read_config(
+            r#"
+            url = "http://default"
+            api_key = "default"
+            send_interval = 1000
+            "#,
+        );
+        assert!(config.check().is_ok());
     }
 }
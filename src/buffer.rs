@@ -0,0 +1,292 @@
+use crate::transaction::WireTransaction;
+use crate::Transaction;
+use std::collections::VecDeque;
+use std::io;
+
+/// The bytes the batch adds around the `Transaction`s: `{"transactions":[` and `]}`.
+const BATCH_OVERHEAD: usize = 19;
+
+/// The [`Transaction`](struct.Transaction.html)s waiting to be sent, along with their estimated serialized sizes
+/// and the sequence numbers they were received in.
+#[derive(Default)]
+pub(crate) struct TransactionBuffer {
+    transactions: VecDeque<Buffered>,
+    bytes: usize,
+}
+
+struct Buffered {
+    transaction: Transaction,
+    size: usize,
+    seq: u64,
+}
+
+impl TransactionBuffer {
+    pub(crate) fn new() -> Self {
+        TransactionBuffer::default()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    /// The estimated size of all the buffered `Transaction`s as a batch, in bytes.
+    pub(crate) fn bytes(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            BATCH_OVERHEAD + self.bytes + self.len() - 1
+        }
+    }
+
+    /// The sequence number of the oldest buffered `Transaction`.
+    pub(crate) fn front_seq(&self) -> Option<u64> {
+        self.transactions.front().map(|buffered| buffered.seq)
+    }
+
+    pub(crate) fn push_back(&mut self, transaction: Transaction, seq: u64) {
+        let size = serialized_size(&transaction);
+        self.bytes += size;
+        self.transactions.push_back(Buffered {
+            transaction,
+            size,
+            seq,
+        });
+    }
+
+    pub(crate) fn pop_front(&mut self) -> Option<Transaction> {
+        let buffered = self.transactions.pop_front()?;
+        self.bytes -= buffered.size;
+        Some(buffered.transaction)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Transaction> {
+        self.transactions
+            .iter()
+            .map(|buffered| &buffered.transaction)
+    }
+
+    /// The sequence number of the oldest `Transaction` accepted by `admits`.
+    pub(crate) fn first_seq_where<F>(&self, admits: F) -> Option<u64>
+    where
+        F: Fn(&Transaction) -> bool,
+    {
+        self.transactions
+            .iter()
+            .find(|buffered| admits(&buffered.transaction))
+            .map(|buffered| buffered.seq)
+    }
+
+    /// Removes the oldest `Transaction`s accepted by `admits` that fit in a batch of at most
+    /// `max_count` transactions and `max_bytes` bytes. The other `Transaction`s stay in the buffer.
+    /// A single `Transaction` larger than `max_bytes` is returned on its own.
+    pub(crate) fn drain_batch<F>(
+        &mut self,
+        max_count: usize,
+        max_bytes: usize,
+        admits: F,
+    ) -> Vec<Transaction>
+    where
+        F: Fn(&Transaction) -> bool,
+    {
+        let mut batch = Vec::new();
+        let mut batch_bytes = BATCH_OVERHEAD;
+        let mut index = 0;
+
+        while batch.len() < max_count {
+            let size = match self.transactions.get(index) {
+                Some(buffered) if !admits(&buffered.transaction) => {
+                    index += 1;
+                    continue;
+                }
+                Some(buffered) => buffered.size,
+                None => break,
+            };
+            let separator = usize::from(!batch.is_empty());
+            if !batch.is_empty() && batch_bytes + separator + size > max_bytes {
+                break;
+            }
+
+            if let Some(buffered) = self.transactions.remove(index) {
+                self.bytes -= buffered.size;
+                batch_bytes += separator + buffered.size;
+                batch.push(buffered.transaction);
+            }
+        }
+
+        batch
+    }
+
+    /// Moves the `Transaction`s of the flow into `target`, keeping both buffers in the order the
+    /// `Transaction`s were received.
+    pub(crate) fn move_flow(&mut self, flow_id: &str, target: &mut TransactionBuffer) {
+        let (moved, kept): (VecDeque<Buffered>, VecDeque<Buffered>) =
+            std::mem::take(&mut self.transactions)
+                .into_iter()
+                .partition(|buffered| buffered.transaction.flow_id == flow_id);
+        self.transactions = kept;
+
+        for buffered in moved {
+            self.bytes -= buffered.size;
+            target.bytes += buffered.size;
+            let index = target
+                .transactions
+                .partition_point(|other| other.seq < buffered.seq);
+            target.transactions.insert(index, buffered);
+        }
+    }
+}
+
+impl FromIterator<Transaction> for TransactionBuffer {
+    fn from_iter<I: IntoIterator<Item = Transaction>>(iter: I) -> Self {
+        let mut buffer = TransactionBuffer::new();
+        for (seq, transaction) in iter.into_iter().enumerate() {
+            buffer.push_back(transaction, seq as u64);
+        }
+        buffer
+    }
+}
+
+/// Counts the bytes written to it.
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the size of the `Transaction` as sent to the Data API, without allocating it.
+pub(crate) fn serialized_size(transaction: &Transaction) -> usize {
+    let mut counter = ByteCounter(0);
+    match serde_json::to_writer(&mut counter, &WireTransaction::from(transaction)) {
+        Ok(_) => counter.0,
+        Err(_) => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Status;
+
+    fn create_trx(message_len: usize) -> Transaction {
+        let mut trx = Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            "flow_id".to_string(),
+            "integration_segment".to_string(),
+        );
+        trx.message = Some("x".repeat(message_len));
+        trx
+    }
+
+    fn create_flow_trx(flow_id: &str) -> Transaction {
+        let mut trx = create_trx(10);
+        trx.flow_id = flow_id.to_string();
+        trx
+    }
+
+    #[test]
+    fn test_serialized_size() {
+        let trx = create_trx(100);
+        assert_eq!(
+            serialized_size(&trx),
+            serde_json::to_vec(&WireTransaction::from(&trx))
+                .unwrap()
+                .len()
+        );
+    }
+
+    #[test]
+    fn test_bytes_matches_serialized_batch() {
+        let buffer: TransactionBuffer = (0..3).map(|_| create_trx(10)).collect();
+        let transactions: Vec<WireTransaction> = buffer
+            .transactions
+            .iter()
+            .map(|buffered| WireTransaction::from(&buffered.transaction))
+            .collect();
+        let batch = serde_json::json!({ "transactions": transactions });
+        assert_eq!(buffer.bytes(), serde_json::to_vec(&batch).unwrap().len());
+    }
+
+    #[test]
+    fn test_drain_batch_by_count() {
+        let mut buffer: TransactionBuffer = (0..5).map(|_| create_trx(10)).collect();
+        assert_eq!(buffer.front_seq(), Some(0));
+        assert_eq!(buffer.drain_batch(3, usize::MAX, |_| true).len(), 3);
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.front_seq(), Some(3));
+    }
+
+    #[test]
+    fn test_drain_batch_by_bytes() {
+        let size = serialized_size(&create_trx(1_000));
+        let mut buffer: TransactionBuffer = (0..5).map(|_| create_trx(1_000)).collect();
+        let batch = buffer.drain_batch(500, BATCH_OVERHEAD + 2 * size + 1, |_| true);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(buffer.len(), 3);
+        assert_eq!(buffer.bytes(), BATCH_OVERHEAD + 3 * size + 2);
+    }
+
+    #[test]
+    fn test_drain_batch_oversized_transaction() {
+        let mut buffer: TransactionBuffer = (0..2).map(|_| create_trx(1_000)).collect();
+        assert_eq!(buffer.drain_batch(500, 100, |_| true).len(), 1);
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_drain_batch_skips_blocked() {
+        let mut buffer: TransactionBuffer = ["a", "b", "a", "c"]
+            .iter()
+            .map(|flow_id| create_flow_trx(flow_id))
+            .collect();
+        let admits = |trx: &Transaction| trx.flow_id != "a";
+        assert_eq!(buffer.first_seq_where(admits), Some(1));
+
+        let batch = buffer.drain_batch(500, usize::MAX, admits);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].flow_id, "b");
+        assert_eq!(batch[1].flow_id, "c");
+        assert_eq!(buffer.len(), 2);
+        assert_eq!(buffer.front_seq(), Some(0));
+        assert_eq!(
+            buffer.bytes(),
+            BATCH_OVERHEAD + 2 * serialized_size(&batch[0]) + 1
+        );
+    }
+
+    #[test]
+    fn test_move_flow() {
+        let mut buffer = TransactionBuffer::new();
+        let mut target = TransactionBuffer::new();
+        buffer.push_back(create_flow_trx("a"), 0);
+        target.push_back(create_flow_trx("b"), 1);
+        buffer.push_back(create_flow_trx("c"), 2);
+        buffer.push_back(create_flow_trx("a"), 3);
+        target.push_back(create_flow_trx("a"), 4);
+
+        buffer.move_flow("a", &mut target);
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.front_seq(), Some(2));
+        let flows: Vec<&str> = target.iter().map(|trx| trx.flow_id.as_str()).collect();
+        assert_eq!(flows, vec!["a", "b", "a", "a"]);
+        assert_eq!(target.front_seq(), Some(0));
+        assert_eq!(
+            target.bytes(),
+            BATCH_OVERHEAD + 4 * serialized_size(&create_flow_trx("a")) + 3
+        );
+    }
+}
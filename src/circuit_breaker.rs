@@ -0,0 +1,218 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use strum_macros::{Display, EnumString};
+
+/// Configuration for the circuit breaker that stops sending while the Data API is unreachable.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CircuitBreakerConfig {
+    /// The number of consecutive failed sends after which the circuit opens.
+    #[serde(alias = "failureThreshold")]
+    pub failure_threshold: u32,
+
+    /// How long the circuit stays open before a single batch is sent to probe the Data API, in milliseconds.
+    #[serde(alias = "openDuration")]
+    pub open_duration: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_threshold: 5,
+            open_duration: 30_000,
+        }
+    }
+}
+
+/// The state of the circuit breaker around the Data API.
+#[derive(Clone, Copy, PartialEq, Eq, EnumString, Display, Debug)]
+pub enum CircuitState {
+    /// The Data API is reachable and batches are sent normally.
+    #[strum(serialize = "closed")]
+    Closed,
+
+    /// The Data API is unreachable. [`Transaction`](struct.Transaction.html)s are buffered but not sent.
+    #[strum(serialize = "open")]
+    Open,
+
+    /// A single batch is being sent to find out whether the Data API is reachable again.
+    #[strum(serialize = "half-open")]
+    HalfOpen,
+}
+
+/// Keeps track of the failed sends and decides whether a batch may be sent.
+pub(crate) struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: CircuitState,
+    failures: u32,
+    opened_at: Instant,
+    probing: bool,
+    shared: Arc<Mutex<CircuitState>>,
+}
+
+impl CircuitBreaker {
+    /// Constructs a closed `CircuitBreaker`, publishing its state to `shared`.
+    pub(crate) fn new(config: CircuitBreakerConfig, shared: Arc<Mutex<CircuitState>>) -> Self {
+        *shared.lock().unwrap() = CircuitState::Closed;
+        CircuitBreaker {
+            config,
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: Instant::now(),
+            probing: false,
+            shared,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Returns `true` if a batch may be sent now. In the half-open state only one batch at a time is allowed.
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> bool {
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => {
+                let open_duration = Duration::from_millis(self.config.open_duration as u64);
+                if now.duration_since(self.opened_at) < open_duration {
+                    return false;
+                }
+                self.set_state(CircuitState::HalfOpen);
+                self.probing = true;
+                true
+            }
+            CircuitState::HalfOpen => {
+                if self.probing {
+                    return false;
+                }
+                self.probing = true;
+                true
+            }
+        }
+    }
+
+    /// Returns `true` while the single probe batch of the half-open state is being sent.
+    pub(crate) fn is_probing(&self) -> bool {
+        self.probing
+    }
+
+    /// Returns the time the open circuit allows a probe batch to be sent.
+    pub(crate) fn reopens_at(&self) -> Option<Instant> {
+        match self.state {
+            CircuitState::Open => {
+                Some(self.opened_at + Duration::from_millis(self.config.open_duration as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Records a send that reached the Data API.
+    pub(crate) fn on_success(&mut self) {
+        self.failures = 0;
+        self.probing = false;
+        self.set_state(CircuitState::Closed);
+    }
+
+    /// Records a send that failed to reach the Data API.
+    pub(crate) fn on_failure(&mut self, now: Instant) {
+        self.failures += 1;
+        self.probing = false;
+        if self.state == CircuitState::HalfOpen || self.failures >= self.config.failure_threshold {
+            if self.state != CircuitState::Open {
+                println!(
+                    "Aino error: Data API unreachable, pausing sending for {} ms",
+                    self.config.open_duration
+                );
+            }
+            self.opened_at = now;
+            self.set_state(CircuitState::Open);
+        }
+    }
+
+    /// Records a send that ended without telling anything about the Data API.
+    pub(crate) fn release(&mut self) {
+        self.probing = false;
+    }
+
+    fn set_state(&mut self, state: CircuitState) {
+        self.state = state;
+        *self.shared.lock().unwrap() = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_breaker() -> (CircuitBreaker, Arc<Mutex<CircuitState>>) {
+        let shared = Arc::new(Mutex::new(CircuitState::Closed));
+        let config = CircuitBreakerConfig {
+            failure_threshold: 2,
+            open_duration: 1_000,
+        };
+        (CircuitBreaker::new(config, shared.clone()), shared)
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let (mut breaker, shared) = create_breaker();
+        let now = Instant::now();
+        breaker.on_failure(now);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire(now));
+
+        breaker.on_failure(now);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(*shared.lock().unwrap(), CircuitState::Open);
+        assert!(!breaker.try_acquire(now));
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let (mut breaker, _) = create_breaker();
+        let now = Instant::now();
+        breaker.on_failure(now);
+        breaker.on_success();
+        breaker.on_failure(now);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_allows_single_probe() {
+        let (mut breaker, shared) = create_breaker();
+        let now = Instant::now();
+        breaker.on_failure(now);
+        breaker.on_failure(now);
+
+        let later = now + Duration::from_millis(1_000);
+        assert!(breaker.try_acquire(later));
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert_eq!(*shared.lock().unwrap(), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire(later));
+        assert!(breaker.is_probing());
+
+        breaker.on_success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.try_acquire(later));
+    }
+
+    #[test]
+    fn test_failed_probe_reopens() {
+        let (mut breaker, _) = create_breaker();
+        let now = Instant::now();
+        breaker.on_failure(now);
+        breaker.on_failure(now);
+
+        let later = now + Duration::from_millis(1_000);
+        assert!(breaker.try_acquire(later));
+        breaker.on_failure(later);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(
+            breaker.reopens_at(),
+            Some(later + Duration::from_millis(1_000))
+        );
+        assert!(!breaker.try_acquire(later + Duration::from_millis(999)));
+        assert!(breaker.try_acquire(later + Duration::from_millis(1_000)));
+    }
+}
@@ -0,0 +1,198 @@
+use crate::Transaction;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// Configuration for the dead-letter file, where the batches permanently rejected by the Data API are written.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeadLetterConfig {
+    /// The file the rejected batches are appended to, one JSON object per line.
+    pub path: PathBuf,
+}
+
+/// A batch of [`Transaction`](struct.Transaction.html)s that the Data API permanently rejected.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct DeadLetter {
+    /// The time the batch was rejected, in milliseconds.
+    pub timestamp: u128,
+
+    /// The HTTP status code of the response.
+    pub status: u16,
+
+    /// The body of the response, usually describing what was wrong with the batch.
+    pub response: String,
+
+    /// The rejected `Transaction`s.
+    pub transactions: Vec<Transaction>,
+
+    /// The name of the destination in [`AinoConfig::destinations`](struct.AinoConfig.html#structfield.destinations)
+    /// that rejected the batch, or `None` for the top-level `url`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+impl DeadLetter {
+    /// Constructs a new `DeadLetter` timestamped with the current time.
+    pub fn new(status: u16, response: String, transactions: Vec<Transaction>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        DeadLetter {
+            timestamp,
+            status,
+            response,
+            transactions,
+            destination: None,
+        }
+    }
+}
+
+/// A user provided function that is called with every [`DeadLetter`](struct.DeadLetter.html).
+///
+/// The handler is called on the agent thread, so it should return quickly.
+#[derive(Clone)]
+pub struct DeadLetterHandler(Arc<dyn Fn(&DeadLetter) + Send + Sync>);
+
+impl DeadLetterHandler {
+    /// Constructs a new `DeadLetterHandler` from the given function.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&DeadLetter) + Send + Sync + 'static,
+    {
+        DeadLetterHandler(Arc::new(handler))
+    }
+}
+
+impl fmt::Debug for DeadLetterHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DeadLetterHandler")
+    }
+}
+
+/// Passes the dead letters on to the dead-letter file and the user's handler.
+pub(crate) struct DeadLetterStore {
+    file: Option<File>,
+    handler: Option<DeadLetterHandler>,
+}
+
+impl DeadLetterStore {
+    /// Opens the dead-letter file for appending, creating it if needed.
+    pub(crate) fn open(
+        config: Option<&DeadLetterConfig>,
+        handler: Option<DeadLetterHandler>,
+    ) -> io::Result<Self> {
+        let file = match config {
+            Some(config) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&config.path)?,
+            ),
+            None => None,
+        };
+        Ok(DeadLetterStore { file, handler })
+    }
+
+    pub(crate) fn store(&mut self, letter: &DeadLetter) {
+        if let Some(handler) = &self.handler {
+            (handler.0)(letter);
+        }
+
+        match &mut self.file {
+            Some(file) => {
+                if let Err(e) = write_line(file, letter) {
+                    println!("Aino error: Failed to write dead letter: {}", e);
+                }
+            }
+            None if self.handler.is_none() => println!(
+                "Aino error: Data API rejected a batch of {} transactions with status {}: {}",
+                letter.transactions.len(),
+                letter.status,
+                letter.response
+            ),
+            None => {}
+        }
+    }
+}
+
+fn write_line(file: &mut File, letter: &DeadLetter) -> io::Result<()> {
+    let mut line = serde_json::to_vec(letter)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Status;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn create_trx() -> Transaction {
+        Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Failure,
+            1,
+            "flow_id".to_string(),
+            "integration_segment".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_store_appends_json_lines() {
+        let path =
+            std::env::temp_dir().join(format!("aino-dead-letter-{}.jsonl", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = DeadLetterConfig { path: path.clone() };
+        let mut store = DeadLetterStore::open(Some(&config), None).unwrap();
+
+        store.store(&DeadLetter::new(
+            400,
+            "bad from".to_string(),
+            vec![create_trx()],
+        ));
+        let trx = create_trx();
+        let mut letter = DeadLetter::new(422, "bad to".to_string(), vec![trx.clone()]);
+        letter.destination = Some("tenant".to_string());
+        store.store(&letter);
+
+        let content = fs::read_to_string(&path).unwrap();
+        let letters: Vec<DeadLetter> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].status, 400);
+        assert_eq!(letters[0].response, "bad from");
+        assert_eq!(letters[0].destination, None);
+        assert_eq!(letters[1].destination.as_deref(), Some("tenant"));
+        assert_eq!(letters[1].status, 422);
+        assert_eq!(letters[1].transactions.len(), 1);
+        assert_eq!(letters[1].transactions[0].id, trx.id);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_store_calls_handler() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let handler = DeadLetterHandler::new(move |letter| {
+            assert_eq!(letter.status, 400);
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let mut store = DeadLetterStore::open(None, Some(handler)).unwrap();
+
+        store.store(&DeadLetter::new(400, String::new(), vec![create_trx()]));
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+}
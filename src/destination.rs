@@ -0,0 +1,226 @@
+use crate::{AinoConfig, Transaction};
+use std::fmt;
+use std::sync::Arc;
+
+/// An additional [`Aino.io`](https://aino.io) endpoint or tenant the agent sends
+/// [`Transaction`](struct.Transaction.html)s to, next to the one in [`AinoConfig`](struct.AinoConfig.html).
+///
+/// Each destination has its own agent thread, queue, retries and circuit breaker. The batching
+/// settings that are not given are taken from `AinoConfig`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DestinationConfig {
+    /// `Aino.io` API URL of the destination.
+    pub url: String,
+
+    /// The API key of the destination.
+    #[serde(alias = "apiKey")]
+    pub api_key: String,
+
+    /// The interval for sending a batch to the destination (optional).
+    #[serde(default, alias = "sendInterval")]
+    pub send_interval: Option<u32>,
+
+    /// The maximum number of `Transaction`s in a single batch (optional). Must be at least 1.
+    #[serde(default, alias = "maxBatchSize")]
+    pub max_batch_size: Option<usize>,
+
+    /// The maximum size of a single batch, in bytes (optional).
+    #[serde(default, alias = "maxBatchBytes")]
+    pub max_batch_bytes: Option<usize>,
+
+    /// The `Transaction`s sent to the destination. Defaults to all of them.
+    #[serde(default)]
+    pub route: Route,
+}
+
+impl DestinationConfig {
+    /// The configuration of the agent thread sending to the destination. Its batches are spooled
+    /// in a subdirectory named after the destination.
+    pub(crate) fn agent_config(&self, name: &str, config: &AinoConfig) -> AinoConfig {
+        let mut config = AinoConfig {
+            url: self.url.clone(),
+            api_key: self.api_key.clone(),
+            send_interval: self.send_interval.unwrap_or(config.send_interval),
+            max_batch_size: self.max_batch_size.unwrap_or(config.max_batch_size),
+            max_batch_bytes: self.max_batch_bytes.unwrap_or(config.max_batch_bytes),
+            route: self.route.clone(),
+            destinations: Default::default(),
+            ..config.clone()
+        };
+        if let Some(spool) = &mut config.spool {
+            spool.directory.push(name);
+        }
+        config
+    }
+}
+
+/// Decides which [`Transaction`](struct.Transaction.html)s are sent to a destination.
+///
+/// In the configuration file, a route is either `"all"`, or a table with one of the keys
+/// `integration_segment`, `from` or `to` listing the accepted values.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Route {
+    /// Every `Transaction`.
+    #[default]
+    All,
+
+    /// The `Transaction`s with one of the integration segments.
+    IntegrationSegment(Vec<String>),
+
+    /// The `Transaction`s sent from one of the applications.
+    From(Vec<String>),
+
+    /// The `Transaction`s sent to one of the applications.
+    To(Vec<String>),
+
+    /// The `Transaction`s the predicate accepts. Can only be set in code.
+    #[serde(skip)]
+    Custom(RoutePredicate),
+}
+
+impl Route {
+    /// Returns `true` if the `Transaction` is sent to the destination.
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        match self {
+            Route::All => true,
+            Route::IntegrationSegment(segments) => {
+                segments.contains(&transaction.integration_segment)
+            }
+            Route::From(applications) => applications.contains(&transaction.from),
+            Route::To(applications) => applications.contains(&transaction.to),
+            Route::Custom(predicate) => predicate.matches(transaction),
+        }
+    }
+}
+
+/// A user provided function that decides whether a [`Transaction`](struct.Transaction.html) is
+/// sent to a destination.
+///
+/// The predicate is called on the thread adding the `Transaction`, so it should return quickly.
+#[derive(Clone)]
+pub struct RoutePredicate(Arc<dyn Fn(&Transaction) -> bool + Send + Sync>);
+
+impl RoutePredicate {
+    /// Constructs a new `RoutePredicate` from the given function.
+    pub fn new<F>(predicate: F) -> Self
+    where
+        F: Fn(&Transaction) -> bool + Send + Sync + 'static,
+    {
+        RoutePredicate(Arc::new(predicate))
+    }
+
+    fn matches(&self, transaction: &Transaction) -> bool {
+        (self.0)(transaction)
+    }
+}
+
+impl fmt::Debug for RoutePredicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("RoutePredicate")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Status;
+
+    fn create_trx(from: &str, to: &str, segment: &str) -> Transaction {
+        Transaction::new(
+            from.to_string(),
+            to.to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            "flow_id".to_string(),
+            segment.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_route_matches() {
+        let trx = create_trx("ERP", "Webshop", "Orders");
+
+        assert!(Route::All.matches(&trx));
+        assert!(Route::IntegrationSegment(vec!["Orders".to_string()]).matches(&trx));
+        assert!(!Route::IntegrationSegment(vec!["Invoices".to_string()]).matches(&trx));
+        assert!(Route::From(vec!["CRM".to_string(), "ERP".to_string()]).matches(&trx));
+        assert!(!Route::From(vec!["Webshop".to_string()]).matches(&trx));
+        assert!(Route::To(vec!["Webshop".to_string()]).matches(&trx));
+        assert!(!Route::To(vec!["ERP".to_string()]).matches(&trx));
+
+        let custom = Route::Custom(RoutePredicate::new(|t| t.operation == "operation"));
+        assert!(custom.matches(&trx));
+    }
+
+    #[test]
+    fn test_route_from_config() {
+        let config = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                [tenant]
+                url = "http://tenant"
+                api_key = "key"
+                send_interval = 500
+                route = { integration_segment = ["Orders"] }
+
+                [everything]
+                url = "http://everything"
+                api_key = "key"
+                route = "all"
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap();
+        let destinations: std::collections::BTreeMap<String, DestinationConfig> =
+            config.try_deserialize().unwrap();
+
+        let tenant = &destinations["tenant"];
+        assert_eq!(tenant.send_interval, Some(500));
+        assert!(tenant.route.matches(&create_trx("a", "b", "Orders")));
+        assert!(!tenant.route.matches(&create_trx("a", "b", "Invoices")));
+        assert!(matches!(destinations["everything"].route, Route::All));
+        assert_eq!(destinations["everything"].max_batch_size, None);
+    }
+
+    #[test]
+    fn test_agent_config() {
+        let config: AinoConfig = config::Config::builder()
+            .add_source(config::File::from_str(
+                r#"
+                url = "http://default"
+                api_key = "default"
+                send_interval = 1000
+                max_batch_size = 100
+
+                [spool]
+                directory = "/var/spool/aino"
+
+                [destinations.tenant]
+                url = "http://tenant"
+                api_key = "tenant"
+                max_batch_size = 10
+                route = { from = ["ERP"] }
+                "#,
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+
+        let tenant = config.destinations["tenant"].agent_config("tenant", &config);
+        assert_eq!(tenant.url, "http://tenant");
+        assert_eq!(tenant.api_key, "tenant");
+        assert_eq!(tenant.send_interval, 1000);
+        assert_eq!(tenant.max_batch_size, 10);
+        assert!(tenant.destinations.is_empty());
+        assert!(matches!(tenant.route, Route::From(_)));
+        assert_eq!(
+            tenant.spool.unwrap().directory,
+            std::path::Path::new("/var/spool/aino/tenant")
+        );
+    }
+}
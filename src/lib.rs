@@ -16,8 +16,10 @@
 //! let config = ainoio_agent::AinoConfig::new()?;
 //!
 //! // Start the Aino agent
-//! // This must be called exactly once before any transactions are sent
-//! ainoio_agent::start(config)?;
+//! // This must be called before any transactions are sent. It fails while the agent is running,
+//! // but the agent can be started again once it has stopped.
+//! // The pending transactions are sent and the agent is stopped when the guard is dropped.
+//! let _guard = ainoio_agent::start_with_guard(config)?;
 //!
 //! let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap();
 //!
@@ -47,21 +49,89 @@ extern crate lazy_static;
 
 mod aino_agent;
 mod aino_config;
+mod buffer;
+mod circuit_breaker;
+mod dead_letter;
+mod destination;
+mod ordering;
+mod queue;
+mod receipt;
+mod retry;
+#[cfg(all(
+    feature = "signals",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_vendor = "apple"
+    )
+))]
+mod signals;
+mod spool;
 mod status;
 mod transaction;
+mod worker;
 
 pub use aino_agent::*;
 pub use aino_config::*;
+pub use circuit_breaker::{CircuitBreakerConfig, CircuitState};
+pub use dead_letter::*;
+pub use destination::*;
+pub use ordering::DeliveryOrder;
+pub use queue::{OverflowPolicy, QueueConfig};
+pub use receipt::*;
+pub use retry::*;
+#[cfg(all(
+    feature = "signals",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "emscripten",
+        target_os = "dragonfly",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd",
+        target_vendor = "apple"
+    )
+))]
+pub use signals::install_signal_handlers;
+pub use spool::SpoolConfig;
 pub use status::*;
 pub use transaction::*;
 
 use std::error::Error;
 use std::fmt;
 
+/// The kind of an [`AinoError`](struct.AinoError.html).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AinoErrorKind {
+    /// The queue of pending [`Transaction`](struct.Transaction.html)s is full.
+    QueueFull,
+
+    /// A request to the Data API timed out. The request is resent, and once the retries run out the
+    /// `Transaction`s are reported [`Dropped`](enum.DeliveryOutcome.html#variant.Dropped) with this reason.
+    Timeout,
+
+    /// The agent has not been started, or it is stopping or stopped.
+    NotRunning,
+
+    /// The agent is already running, so it can not be started again before it is stopped.
+    AlreadyRunning,
+
+    /// Any other error.
+    Other,
+}
+
 /// Error object for [`Aino.io`](https://aino.io) agent
 #[derive(Debug)]
 pub struct AinoError {
     msg: String,
+    kind: AinoErrorKind,
 }
 
 impl fmt::Display for AinoError {
@@ -73,8 +143,67 @@ impl fmt::Display for AinoError {
 impl AinoError {
     /// Construct a new `AinoError`
     pub fn new(msg: String) -> Self {
-        AinoError { msg }
+        AinoError::with_kind(AinoErrorKind::Other, msg)
+    }
+
+    /// Construct a new `AinoError` of the given kind
+    pub fn with_kind(kind: AinoErrorKind, msg: String) -> Self {
+        AinoError { msg, kind }
+    }
+
+    /// Returns the kind of the error
+    pub fn kind(&self) -> AinoErrorKind {
+        self.kind
+    }
+
+    /// Returns `true` if the failed operation is worth retrying
+    pub fn is_retryable(&self) -> bool {
+        matches!(self.kind, AinoErrorKind::QueueFull | AinoErrorKind::Timeout)
     }
 }
 
 impl Error for AinoError {}
+
+/// The error returned by [`try_add_transaction`](fn.try_add_transaction.html), which hands the
+/// [`Transaction`](struct.Transaction.html) that was not added back to the caller.
+#[derive(Debug)]
+pub struct TryAddError {
+    transaction: Box<Transaction>,
+    error: AinoError,
+}
+
+impl TryAddError {
+    pub(crate) fn new(transaction: Box<Transaction>, error: AinoError) -> Self {
+        TryAddError { transaction, error }
+    }
+
+    /// Returns the kind of the error, either [`AinoErrorKind::NotRunning`](enum.AinoErrorKind.html)
+    /// or [`AinoErrorKind::QueueFull`](enum.AinoErrorKind.html)
+    pub fn kind(&self) -> AinoErrorKind {
+        self.error.kind()
+    }
+
+    /// Returns the `Transaction` that was not added
+    pub fn transaction(&self) -> &Transaction {
+        &self.transaction
+    }
+
+    /// Takes back the `Transaction` that was not added
+    pub fn into_transaction(self) -> Transaction {
+        *self.transaction
+    }
+}
+
+impl fmt::Display for TryAddError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.error.fmt(fmt)
+    }
+}
+
+impl Error for TryAddError {}
+
+impl From<TryAddError> for AinoError {
+    fn from(error: TryAddError) -> Self {
+        error.error
+    }
+}
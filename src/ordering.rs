@@ -0,0 +1,99 @@
+use crate::Transaction;
+use std::collections::HashSet;
+
+/// The order in which the [`Transaction`](struct.Transaction.html)s are delivered to the Data API.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryOrder {
+    /// Batches are sent in parallel, so retried or slow batches may arrive out of order.
+    #[default]
+    Any,
+
+    /// The `Transaction`s with the same `flow_id` are delivered in the order they were added.
+    /// A flow is not sent while an earlier batch with the same flow is being sent or waiting to
+    /// be resent, but unrelated flows are still sent in parallel.
+    PerFlow,
+}
+
+/// Keeps track of the flows that can not be sent right now, because an earlier `Transaction`
+/// of the flow has not been delivered yet. Lets everything through unless the order is per flow.
+pub(crate) struct FlowGate {
+    enabled: bool,
+    blocked: HashSet<String>,
+}
+
+impl FlowGate {
+    pub(crate) fn new(order: DeliveryOrder) -> Self {
+        FlowGate {
+            enabled: order == DeliveryOrder::PerFlow,
+            blocked: HashSet::new(),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Blocks the flows of the `Transaction`s.
+    pub(crate) fn block<'a>(&mut self, transactions: impl IntoIterator<Item = &'a Transaction>) {
+        if !self.enabled {
+            return;
+        }
+        for transaction in transactions {
+            if !self.blocked.contains(&transaction.flow_id) {
+                self.blocked.insert(transaction.flow_id.clone());
+            }
+        }
+    }
+
+    /// Returns `true` if the `Transaction` can be sent.
+    pub(crate) fn admits(&self, transaction: &Transaction) -> bool {
+        !self.enabled || !self.blocked.contains(&transaction.flow_id)
+    }
+
+    /// Returns `true` if all the `Transaction`s can be sent.
+    pub(crate) fn admits_all(&self, transactions: &[Transaction]) -> bool {
+        transactions
+            .iter()
+            .all(|transaction| self.admits(transaction))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Status;
+
+    fn create_trx(flow_id: &str) -> Transaction {
+        Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            flow_id.to_string(),
+            "integration_segment".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_per_flow_blocks_sent_flows() {
+        let mut gate = FlowGate::new(DeliveryOrder::PerFlow);
+        let batch = vec![create_trx("a"), create_trx("b")];
+        assert!(gate.admits_all(&batch));
+
+        gate.block(&batch);
+        assert!(!gate.admits(&create_trx("a")));
+        assert!(gate.admits(&create_trx("c")));
+        assert!(!gate.admits_all(&[create_trx("c"), create_trx("b")]));
+    }
+
+    #[test]
+    fn test_any_order_admits_everything() {
+        let mut gate = FlowGate::new(DeliveryOrder::Any);
+        let batch = vec![create_trx("a")];
+        gate.block(&batch);
+        assert!(!gate.is_enabled());
+        assert!(gate.admits_all(&batch));
+    }
+}
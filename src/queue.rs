@@ -0,0 +1,426 @@
+use crate::{AinoError, AinoErrorKind};
+use std::pin::pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// What to do with a new [`Transaction`](struct.Transaction.html) when the queue is full.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// The new `Transaction` is dropped. An urgent `Transaction` makes room by dropping the oldest
+    /// routine one instead.
+    DropNewest,
+
+    /// The oldest buffered `Transaction` is dropped to make room for the new one. Routine
+    /// `Transaction`s are dropped before urgent ones.
+    DropOldest,
+
+    /// The caller is blocked until there is room, or until `block_timeout` has elapsed.
+    Block,
+
+    /// The new `Transaction` is refused with an [`AinoErrorKind::QueueFull`](enum.AinoErrorKind.html) error.
+    Error,
+}
+
+/// Configuration for the queue of [`Transaction`](struct.Transaction.html)s waiting to be delivered.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct QueueConfig {
+    /// The maximum number of `Transaction`s the agent holds in memory, including the ones being sent.
+    #[serde(alias = "maxSize")]
+    pub max_size: usize,
+
+    /// What to do when the queue is full.
+    pub overflow: OverflowPolicy,
+
+    /// How long [`OverflowPolicy::Block`](enum.OverflowPolicy.html) waits for room, in milliseconds.
+    #[serde(alias = "blockTimeout")]
+    pub block_timeout: u32,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            max_size: 100_000,
+            overflow: OverflowPolicy::DropNewest,
+            block_timeout: 1_000,
+        }
+    }
+}
+
+/// The result of asking the queue for room for a new `Transaction`.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Admission {
+    Accepted,
+    Dropped,
+}
+
+/// Counts the `Transaction`s held by the agent and applies the overflow policy.
+pub(crate) struct TransactionQueue {
+    config: QueueConfig,
+    len: AtomicUsize,
+    /// Only taken by the callers waiting for room, and to wake them up.
+    waiting: Mutex<()>,
+    not_full: Condvar,
+    /// Wakes the async callers waiting for room.
+    room: Notify,
+    dropped: AtomicU64,
+}
+
+impl TransactionQueue {
+    pub(crate) fn new(config: QueueConfig) -> Self {
+        TransactionQueue {
+            config,
+            len: AtomicUsize::new(0),
+            waiting: Mutex::new(()),
+            not_full: Condvar::new(),
+            room: Notify::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Makes room for a new `Transaction` according to the overflow policy. An `urgent` `Transaction`
+    /// is not dropped by [`OverflowPolicy::DropNewest`](enum.OverflowPolicy.html), but makes room by
+    /// dropping the oldest routine one instead.
+    pub(crate) fn admit(&self, urgent: bool) -> Result<Admission, AinoError> {
+        if let Some(admission) = self.admit_now(urgent)? {
+            return Ok(admission);
+        }
+
+        let deadline = Instant::now() + block_timeout(&self.config);
+        let mut waiting = self.waiting.lock().unwrap();
+        while !self.try_reserve() {
+            let now = Instant::now();
+            if now >= deadline {
+                self.record_drops(1);
+                return Err(queue_full());
+            }
+            waiting = self
+                .not_full
+                .wait_timeout(waiting, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Ok(Admission::Accepted)
+    }
+
+    /// An async variant of `admit`, which waits for room without blocking the thread.
+    pub(crate) async fn admit_async(&self, urgent: bool) -> Result<Admission, AinoError> {
+        let deadline = tokio::time::Instant::now() + block_timeout(&self.config);
+        loop {
+            // Registered before checking, so that room made in between is not missed
+            let mut notified = pin!(self.room.notified());
+            notified.as_mut().enable();
+
+            if let Some(admission) = self.admit_now(urgent)? {
+                return Ok(admission);
+            }
+            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                self.record_drops(1);
+                return Err(queue_full());
+            }
+        }
+    }
+
+    /// Takes room for a new `Transaction` if there is any, without waiting, locking or applying
+    /// the overflow policy.
+    pub(crate) fn try_admit(&self) -> Result<(), AinoError> {
+        if self.try_reserve() {
+            Ok(())
+        } else {
+            Err(queue_full())
+        }
+    }
+
+    /// Applies the overflow policy without waiting. Returns `None` if the caller has to wait for room.
+    fn admit_now(&self, urgent: bool) -> Result<Option<Admission>, AinoError> {
+        if self.try_reserve() {
+            return Ok(Some(Admission::Accepted));
+        }
+
+        match self.config.overflow {
+            OverflowPolicy::DropNewest if !urgent => {
+                self.record_drops(1);
+                Ok(Some(Admission::Dropped))
+            }
+            OverflowPolicy::DropNewest | OverflowPolicy::DropOldest => {
+                // The agent thread drops the oldest buffered `Transaction` when it receives this one
+                self.len.fetch_add(1, Ordering::SeqCst);
+                Ok(Some(Admission::Accepted))
+            }
+            OverflowPolicy::Block => Ok(None),
+            OverflowPolicy::Error => {
+                self.record_drops(1);
+                Err(queue_full())
+            }
+        }
+    }
+
+    /// Counts a new `Transaction` if the queue is not full.
+    fn try_reserve(&self) -> bool {
+        let max_size = self.config.max_size;
+        self.len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                (len < max_size).then_some(len + 1)
+            })
+            .is_ok()
+    }
+
+    /// Counts `Transaction`s that were not admitted through `admit`.
+    #[cfg(test)]
+    pub(crate) fn reserve(&self, count: usize) {
+        self.len.fetch_add(count, Ordering::SeqCst);
+    }
+
+    /// Counts the `Transaction`s of a batch replayed from the spool if they fit in half of the
+    /// queue, which leaves the other half for new `Transaction`s. A larger batch is let in once
+    /// the queue is empty, so that it does not stay in the spool forever.
+    pub(crate) fn try_reserve_replayed(&self, count: usize) -> bool {
+        let limit = self.config.max_size / 2;
+        self.len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                (len == 0 || len + count <= limit).then_some(len + count)
+            })
+            .is_ok()
+    }
+
+    /// Frees the room of `Transaction`s the agent no longer holds.
+    pub(crate) fn release(&self, count: usize) {
+        self.free(count);
+        // Taking the lock makes sure a blocked caller is either waiting or sees the room
+        drop(self.waiting.lock().unwrap());
+        self.not_full.notify_all();
+        self.room.notify_waiters();
+    }
+
+    /// Gives back the room taken by `try_admit` for a `Transaction` that was not handed over,
+    /// without locking. A caller that is just about to block in `admit` may miss it, and then
+    /// waits for the next `release`.
+    pub(crate) fn unreserve(&self, count: usize) {
+        self.free(count);
+        self.not_full.notify_all();
+        self.room.notify_waiters();
+    }
+
+    fn free(&self, count: usize) {
+        let _ = self
+            .len
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |len| {
+                Some(len.saturating_sub(count))
+            });
+    }
+
+    /// Returns `true` once three quarters of the queue are taken, which is when the agent starts
+    /// moving the waiting `Transaction`s into the spool.
+    pub(crate) fn is_nearly_full(&self) -> bool {
+        let max_size = self.config.max_size;
+        self.len.load(Ordering::SeqCst) >= max_size - max_size / 4
+    }
+
+    /// The number of `Transaction`s above the maximum size, which should be dropped.
+    pub(crate) fn excess(&self) -> usize {
+        self.len
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.config.max_size)
+    }
+
+    pub(crate) fn record_drops(&self, count: usize) {
+        self.dropped.fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    /// The total number of `Transaction`s dropped because the queue was full.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn block_timeout(config: &QueueConfig) -> Duration {
+    Duration::from_millis(config.block_timeout as u64)
+}
+
+fn queue_full() -> AinoError {
+    AinoError::with_kind(
+        AinoErrorKind::QueueFull,
+        "Aino error: The transaction queue is full".to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn create_queue(overflow: OverflowPolicy) -> TransactionQueue {
+        TransactionQueue::new(QueueConfig {
+            max_size: 2,
+            overflow,
+            block_timeout: 50,
+        })
+    }
+
+    #[test]
+    fn test_admit_until_full() {
+        let queue = create_queue(OverflowPolicy::DropNewest);
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+        assert_eq!(queue.admit(false).unwrap(), Admission::Dropped);
+        assert_eq!(queue.dropped(), 1);
+
+        queue.release(1);
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+    }
+
+    #[test]
+    fn test_urgent_is_not_dropped() {
+        let queue = create_queue(OverflowPolicy::DropNewest);
+        queue.admit(false).unwrap();
+        queue.admit(false).unwrap();
+        assert_eq!(queue.admit(true).unwrap(), Admission::Accepted);
+        assert_eq!(queue.excess(), 1);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = create_queue(OverflowPolicy::DropOldest);
+        queue.admit(false).unwrap();
+        queue.admit(false).unwrap();
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+        assert_eq!(queue.excess(), 1);
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn test_error() {
+        let queue = create_queue(OverflowPolicy::Error);
+        queue.admit(false).unwrap();
+        queue.admit(false).unwrap();
+        let err = queue.admit(false).unwrap_err();
+        assert_eq!(err.kind(), AinoErrorKind::QueueFull);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn test_block_times_out() {
+        let queue = create_queue(OverflowPolicy::Block);
+        queue.admit(false).unwrap();
+        queue.admit(false).unwrap();
+        let start = Instant::now();
+        let err = queue.admit(false).unwrap_err();
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert_eq!(err.kind(), AinoErrorKind::QueueFull);
+        assert_eq!(queue.dropped(), 1);
+    }
+
+    #[test]
+    fn test_block_until_released() {
+        let queue = Arc::new(TransactionQueue::new(QueueConfig {
+            max_size: 1,
+            overflow: OverflowPolicy::Block,
+            block_timeout: 10_000,
+        }));
+        queue.admit(false).unwrap();
+
+        let releaser = queue.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            releaser.release(1);
+        });
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+        handle.join().unwrap();
+        assert_eq!(queue.dropped(), 0);
+    }
+
+    #[test]
+    fn test_try_reserve_replayed() {
+        let queue = TransactionQueue::new(QueueConfig {
+            max_size: 4,
+            overflow: OverflowPolicy::DropNewest,
+            block_timeout: 50,
+        });
+        assert!(queue.try_reserve_replayed(2));
+        assert!(!queue.try_reserve_replayed(1));
+        queue.release(2);
+
+        // A batch larger than half of the queue only fits in an empty one
+        assert!(queue.try_reserve_replayed(3));
+        assert!(!queue.try_reserve_replayed(1));
+        assert_eq!(queue.excess(), 0);
+    }
+
+    #[test]
+    fn test_is_nearly_full() {
+        let queue = TransactionQueue::new(QueueConfig {
+            max_size: 4,
+            overflow: OverflowPolicy::DropNewest,
+            block_timeout: 50,
+        });
+        queue.reserve(2);
+        assert!(!queue.is_nearly_full());
+        queue.reserve(1);
+        assert!(queue.is_nearly_full());
+    }
+
+    #[test]
+    fn test_try_admit() {
+        let queue = create_queue(OverflowPolicy::DropOldest);
+        queue.try_admit().unwrap();
+        queue.try_admit().unwrap();
+        let err = queue.try_admit().unwrap_err();
+        assert_eq!(err.kind(), AinoErrorKind::QueueFull);
+        assert_eq!(queue.excess(), 0);
+        assert_eq!(queue.dropped(), 0);
+
+        queue.unreserve(1);
+        queue.try_admit().unwrap();
+    }
+
+    #[test]
+    fn test_unreserve_wakes_blocked_caller() {
+        let queue = Arc::new(TransactionQueue::new(QueueConfig {
+            max_size: 1,
+            overflow: OverflowPolicy::Block,
+            block_timeout: 10_000,
+        }));
+        queue.try_admit().unwrap();
+
+        let unreserver = queue.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            // Holding the lock shows that `unreserve` does not take it
+            let _waiting = unreserver.waiting.lock().unwrap();
+            unreserver.unreserve(1);
+        });
+        assert_eq!(queue.admit(false).unwrap(), Admission::Accepted);
+        handle.join().unwrap();
+        assert_eq!(queue.excess(), 0);
+    }
+
+    #[test]
+    fn test_admit_async_waits_for_room() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let queue = Arc::new(create_queue(OverflowPolicy::Block));
+        queue.admit(false).unwrap();
+        queue.admit(false).unwrap();
+
+        let err = rt.block_on(queue.admit_async(false)).unwrap_err();
+        assert_eq!(err.kind(), AinoErrorKind::QueueFull);
+
+        let releaser = queue.clone();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            releaser.release(1);
+        });
+        // The block timeout is 50 ms, and the other thread makes room after 20 ms
+        let admission = rt.block_on(queue.admit_async(false)).unwrap();
+        assert_eq!(admission, Admission::Accepted);
+        handle.join().unwrap();
+        assert_eq!(queue.dropped(), 1);
+    }
+}
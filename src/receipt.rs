@@ -0,0 +1,173 @@
+use crate::{AinoErrorKind, Transaction};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+use tokio::sync::oneshot;
+
+/// A receipt for a batch of [`Transaction`](struct.Transaction.html)s the Data API accepted.
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+#[non_exhaustive]
+pub struct DeliveryReceipt {
+    /// The ID the Data API gave to the batch, if the response contained one.
+    pub batch_id: Option<String>,
+
+    /// The time the batch was accepted, in milliseconds.
+    pub timestamp: u128,
+
+    /// The `Transaction`s in the batch.
+    pub transactions: Vec<Transaction>,
+
+    /// The name of the destination in [`AinoConfig::destinations`](struct.AinoConfig.html#structfield.destinations)
+    /// the batch was sent to, or `None` for the top-level `url`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+impl DeliveryReceipt {
+    /// Constructs a new `DeliveryReceipt` timestamped with the current time.
+    pub fn new(batch_id: Option<String>, transactions: Vec<Transaction>) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        DeliveryReceipt {
+            batch_id,
+            timestamp,
+            transactions,
+            destination: None,
+        }
+    }
+}
+
+/// A user provided function that is called with every [`DeliveryReceipt`](struct.DeliveryReceipt.html).
+///
+/// The handler is called on the agent thread, so it should return quickly. To process the
+/// receipts elsewhere, pass them on to a channel.
+#[derive(Clone)]
+pub struct ReceiptHandler(Arc<dyn Fn(&DeliveryReceipt) + Send + Sync>);
+
+impl ReceiptHandler {
+    /// Constructs a new `ReceiptHandler` from the given function.
+    pub fn new<F>(handler: F) -> Self
+    where
+        F: Fn(&DeliveryReceipt) + Send + Sync + 'static,
+    {
+        ReceiptHandler(Arc::new(handler))
+    }
+
+    pub(crate) fn handle(&self, receipt: &DeliveryReceipt) {
+        (self.0)(receipt)
+    }
+}
+
+impl fmt::Debug for ReceiptHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ReceiptHandler")
+    }
+}
+
+/// What finally happened to a [`Transaction`](struct.Transaction.html) added with
+/// [`add_transaction_tracked`](fn.add_transaction_tracked.html).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeliveryOutcome {
+    /// The Data API accepted the `Transaction`.
+    Delivered {
+        /// The ID the Data API gave to the batch, if the response contained one.
+        batch_id: Option<String>,
+    },
+
+    /// The Data API permanently rejected the batch the `Transaction` was in.
+    Rejected {
+        /// The HTTP status of the response.
+        status: u16,
+
+        /// The body of the response.
+        reason: String,
+    },
+
+    /// The agent gave up on the `Transaction`: the queue was full, the retries ran out, or the
+    /// agent was stopped before it was delivered. A `Transaction` stored in the spool is also
+    /// reported as dropped, even though the next run will resend it.
+    Dropped {
+        /// Why the `Transaction` was given up on: [`QueueFull`](enum.AinoErrorKind.html#variant.QueueFull),
+        /// [`NotRunning`](enum.AinoErrorKind.html#variant.NotRunning) when the agent was stopped,
+        /// or the kind of the last failed send, such as [`Timeout`](enum.AinoErrorKind.html#variant.Timeout).
+        reason: AinoErrorKind,
+    },
+}
+
+/// Resolves to the [`DeliveryOutcome`](enum.DeliveryOutcome.html) of a single
+/// [`Transaction`](struct.Transaction.html) once the agent knows it.
+///
+/// The handle can be awaited, or waited on with [`wait`](struct.DeliveryHandle.html#method.wait).
+#[derive(Debug)]
+pub struct DeliveryHandle {
+    receiver: oneshot::Receiver<DeliveryOutcome>,
+}
+
+impl DeliveryHandle {
+    pub(crate) fn new() -> (oneshot::Sender<DeliveryOutcome>, Self) {
+        let (sender, receiver) = oneshot::channel();
+        (sender, DeliveryHandle { receiver })
+    }
+
+    /// Constructs a `DeliveryHandle` that has already resolved to `outcome`.
+    pub(crate) fn resolved(outcome: DeliveryOutcome) -> Self {
+        let (sender, handle) = DeliveryHandle::new();
+        let _ = sender.send(outcome);
+        handle
+    }
+
+    /// Blocks the current thread until the outcome is known.
+    pub fn wait(self) -> DeliveryOutcome {
+        futures::executor::block_on(self)
+    }
+}
+
+impl Future for DeliveryHandle {
+    type Output = DeliveryOutcome;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The agent lets go of the sender without an outcome only when it no longer holds the `Transaction`
+        Pin::new(&mut self.receiver).poll(cx).map(|outcome| {
+            outcome.unwrap_or(DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::NotRunning,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_resolves_to_outcome() {
+        let (sender, handle) = DeliveryHandle::new();
+        let outcome = DeliveryOutcome::Delivered {
+            batch_id: Some("b-1".to_string()),
+        };
+        sender.send(outcome.clone()).unwrap();
+        assert_eq!(handle.wait(), outcome);
+    }
+
+    #[test]
+    fn test_handle_without_outcome_is_dropped() {
+        let (sender, handle) = DeliveryHandle::new();
+        drop(sender);
+        assert_eq!(
+            handle.wait(),
+            DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::NotRunning
+            }
+        );
+        let dropped = DeliveryOutcome::Dropped {
+            reason: AinoErrorKind::QueueFull,
+        };
+        assert_eq!(DeliveryHandle::resolved(dropped.clone()).wait(), dropped);
+    }
+}
@@ -0,0 +1,109 @@
+use rand::Rng;
+use std::cmp::min;
+use std::time::Duration;
+
+/// The policy for resending batches that could not be delivered to [`Aino.io`](https://aino.io).
+///
+/// The delay between attempts grows exponentially from `base_delay` up to `max_delay`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RetryConfig {
+    /// The maximum number of times a batch is sent before it is given up, counting the throttled
+    /// attempts. `1` disables resending.
+    #[serde(alias = "maxAttempts")]
+    pub max_attempts: u32,
+
+    /// The delay before the first resend, in milliseconds.
+    #[serde(alias = "baseDelay")]
+    pub base_delay: u32,
+
+    /// The upper limit for the delay between two attempts, in milliseconds.
+    #[serde(alias = "maxDelay")]
+    pub max_delay: u32,
+
+    /// Randomizes each delay to between half and all of its computed value, so that
+    /// several agents do not resend in lockstep.
+    pub jitter: bool,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_attempts: 5,
+            base_delay: 500,
+            max_delay: 30_000,
+            jitter: true,
+        }
+    }
+}
+
+impl RetryConfig {
+    /// Returns `true` if a batch that has been sent `attempts` times may still be resent.
+    pub(crate) fn can_retry(&self, attempts: u32) -> bool {
+        attempts < self.max_attempts
+    }
+
+    /// Computes the delay before the next attempt of a batch that has failed `attempts` times.
+    pub(crate) fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(31);
+        let delay = min(
+            (self.base_delay as u64).saturating_mul(1 << exponent),
+            self.max_delay as u64,
+        );
+
+        let delay = if self.jitter && delay > 0 {
+            rand::thread_rng().gen_range(delay / 2..=delay)
+        } else {
+            delay
+        };
+
+        Duration::from_millis(delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config(jitter: bool) -> RetryConfig {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay: 100,
+            max_delay: 1_000,
+            jitter,
+        }
+    }
+
+    #[test]
+    fn test_can_retry() {
+        let config = create_config(false);
+        assert!(config.can_retry(1));
+        assert!(config.can_retry(3));
+        assert!(!config.can_retry(4));
+    }
+
+    #[test]
+    fn test_backoff_grows_exponentially() {
+        let config = create_config(false);
+        assert_eq!(config.backoff(1), Duration::from_millis(100));
+        assert_eq!(config.backoff(2), Duration::from_millis(200));
+        assert_eq!(config.backoff(3), Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let config = create_config(false);
+        assert_eq!(config.backoff(5), Duration::from_millis(1_000));
+        assert_eq!(config.backoff(100), Duration::from_millis(1_000));
+    }
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        let config = create_config(true);
+        for _ in 0..100 {
+            let delay = config.backoff(3);
+            assert!(delay >= Duration::from_millis(200));
+            assert!(delay <= Duration::from_millis(400));
+        }
+    }
+}
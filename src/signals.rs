@@ -0,0 +1,308 @@
+use crate::{aino_agent, AinoError};
+use libc::c_int;
+use std::io;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::thread;
+use std::time::Duration;
+
+// The module is only built for the targets listed in `lib.rs`, which each have one of these
+#[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+use libc::__errno as errno_location;
+#[cfg(any(target_os = "linux", target_os = "dragonfly", target_os = "emscripten"))]
+use libc::__errno_location as errno_location;
+#[cfg(any(target_vendor = "apple", target_os = "freebsd"))]
+use libc::__error as errno_location;
+
+/// The signals that flush the agent.
+const SIGNALS: [c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// The write end of the pipe the signal handler wakes the watcher thread with.
+static PIPE: AtomicI32 = AtomicI32::new(-1);
+
+/// Installs handlers for `SIGTERM` and `SIGINT` that flush the agent before the process exits.
+///
+/// When either signal arrives, the [`Transaction`](struct.Transaction.html)s added so far to the
+/// agent started with [`start`](fn.start.html) are flushed, waiting at most `grace_period`. The
+/// [`Agent`](struct.Agent.html)s created with `Agent::new` are not flushed. Then the previous handlers are restored and the signal
+/// is raised again, so the previous handler runs, or the process exits the way it would have
+/// without the agent. Signals that were ignored stay ignored.
+///
+/// Only available with the `signals` feature on Unix. Can be called once, before or after
+/// [`start`](fn.start.html).
+pub fn install_signal_handlers(grace_period: Duration) -> Result<(), AinoError> {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Err(AinoError::new(
+            "Aino error: The signal handlers are already installed".to_string(),
+        ));
+    }
+
+    install(grace_period).map_err(|e| {
+        INSTALLED.store(false, Ordering::SeqCst);
+        AinoError::new(format!(
+            "Aino error: Failed to install the signal handlers: {}",
+            e
+        ))
+    })
+}
+
+fn install(grace_period: Duration) -> io::Result<()> {
+    let fds = open_pipe()?;
+    PIPE.store(fds[1], Ordering::SeqCst);
+
+    let mut previous = Vec::new();
+    for signal in SIGNALS {
+        match replace_handler(signal) {
+            Ok(Some(action)) => previous.push((signal, action)),
+            Ok(None) => {}
+            Err(e) => {
+                restore(&previous);
+                return Err(e);
+            }
+        }
+    }
+
+    thread::Builder::new()
+        .name("aino-signals".to_string())
+        .spawn(move || watch(fds[0], previous, grace_period))
+        .map(|_| ())
+}
+
+/// Opens the pipe the signal handler wakes the watcher thread with. Child processes do not inherit
+/// it, and writing to it never blocks, so a burst of signals cannot hang the signal handler.
+fn open_pipe() -> io::Result<[c_int; 2]> {
+    let mut fds = [0; 2];
+    #[cfg(not(target_vendor = "apple"))]
+    let opened = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    #[cfg(target_vendor = "apple")]
+    let opened = unsafe { libc::pipe(fds.as_mut_ptr()) };
+    if opened != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    #[cfg(target_vendor = "apple")]
+    let flags = [
+        (fds[0], libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC),
+        (fds[1], libc::F_GETFD, libc::F_SETFD, libc::FD_CLOEXEC),
+        (fds[1], libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK),
+    ];
+    #[cfg(not(target_vendor = "apple"))]
+    let flags = [(fds[1], libc::F_GETFL, libc::F_SETFL, libc::O_NONBLOCK)];
+    for (fd, get, set, flag) in flags {
+        let flags = unsafe { libc::fcntl(fd, get) };
+        if flags < 0 || unsafe { libc::fcntl(fd, set, flags | flag) } < 0 {
+            let error = io::Error::last_os_error();
+            unsafe {
+                libc::close(fds[0]);
+                libc::close(fds[1]);
+            }
+            return Err(error);
+        }
+    }
+    Ok(fds)
+}
+
+/// Installs the handler for the signal, unless the signal is ignored. Returns the previous action.
+fn replace_handler(signal: c_int) -> io::Result<Option<libc::sigaction>> {
+    unsafe {
+        let mut previous: libc::sigaction = std::mem::zeroed();
+        if libc::sigaction(signal, ptr::null(), &mut previous) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if previous.sa_sigaction == libc::SIG_IGN {
+            return Ok(None);
+        }
+
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = handle_signal as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        libc::sigemptyset(&mut action.sa_mask);
+        if libc::sigaction(signal, &action, ptr::null_mut()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Some(previous))
+    }
+}
+
+fn restore(previous: &[(c_int, libc::sigaction)]) {
+    for (signal, action) in previous {
+        unsafe {
+            libc::sigaction(*signal, action, ptr::null_mut());
+        }
+    }
+}
+
+/// Only wakes the watcher thread, as flushing is not safe inside a signal handler. Keeps `errno`
+/// as it was for the code the signal interrupted.
+extern "C" fn handle_signal(signal: c_int) {
+    let byte = signal as u8;
+    unsafe {
+        let errno = *errno_location();
+        libc::write(
+            PIPE.load(Ordering::SeqCst),
+            &byte as *const u8 as *const libc::c_void,
+            1,
+        );
+        *errno_location() = errno;
+    }
+}
+
+/// Waits for a signal, flushes the agent and hands the signal over to the previous handler.
+fn watch(fd: c_int, previous: Vec<(c_int, libc::sigaction)>, grace_period: Duration) {
+    let mut byte = 0u8;
+    loop {
+        let read = unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+        if read == 1 {
+            break;
+        }
+        if read < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+            continue;
+        }
+        return;
+    }
+
+    if let Err(e) = flush(grace_period) {
+        println!("{}", e);
+    }
+
+    restore(&previous);
+    unsafe {
+        libc::raise(c_int::from(byte));
+    }
+}
+
+fn flush(grace_period: Duration) -> Result<(), AinoError> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .map_err(|e| AinoError::new(format!("Aino error: {}", e)))?;
+    match rt.block_on(async { tokio::time::timeout(grace_period, aino_agent::flush_async()).await })
+    {
+        Ok(result) => result,
+        Err(_) => Err(AinoError::new(format!(
+            "Aino error: The flush did not finish within {} ms",
+            grace_period.as_millis()
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AinoConfig, Status, Transaction};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Command;
+    use std::time::Instant;
+
+    /// Tells the test process started by the test that it is the one receiving the signal.
+    const CHILD_URL: &str = "AINO_SIGNALS_TEST_URL";
+
+    /// The signal ends the process, so the agent is run in a child process running this test again.
+    #[test]
+    fn test_sigterm_flushes_default_agent() {
+        if let Ok(url) = std::env::var(CHILD_URL) {
+            return raise_sigterm(url);
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut child = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "signals::tests::test_sigterm_flushes_default_agent",
+                "--exact",
+                "--nocapture",
+            ])
+            .env(
+                CHILD_URL,
+                format!("http://{}/", listener.local_addr().unwrap()),
+            )
+            .spawn()
+            .unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut stream = loop {
+            match listener.accept() {
+                Ok((stream, _)) => break stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if let Some(status) = child.try_wait().unwrap() {
+                        panic!("the agent exited without flushing: {}", status);
+                    }
+                    assert!(Instant::now() < deadline, "the agent was not flushed");
+                    thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => panic!("{}", e),
+            }
+        };
+        let request = read_request(&mut stream);
+        assert!(request.contains(r#""flowId":"signal""#));
+        let body = r#"{"batch":"batch"}"#;
+        write!(
+            stream,
+            "HTTP/1.1 202 Accepted\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        // The signal was passed on to the default action, which terminates the process
+        let status = child.wait().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+
+    fn raise_sigterm(url: String) {
+        let config = AinoConfig {
+            url,
+            send_interval: 60_000,
+            ..AinoConfig::default()
+        };
+        aino_agent::start(config).unwrap();
+        aino_agent::add_transaction(Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            "signal".to_string(),
+            "integration_segment".to_string(),
+        ))
+        .unwrap();
+        install_signal_handlers(Duration::from_secs(5)).unwrap();
+
+        unsafe {
+            libc::raise(libc::SIGTERM);
+        }
+        thread::sleep(Duration::from_secs(10));
+        panic!("the process was not terminated by the signal");
+    }
+
+    /// Reads an HTTP request with a `Content-Length`.
+    fn read_request(stream: &mut TcpStream) -> String {
+        stream.set_nonblocking(false).unwrap();
+        let mut request = Vec::new();
+        let mut chunk = [0; 4096];
+        loop {
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "the connection was closed mid-request");
+            request.extend_from_slice(&chunk[..read]);
+
+            let text = String::from_utf8_lossy(&request);
+            if let Some(end) = text.find("\r\n\r\n") {
+                let length = text[..end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())?
+                    })
+                    .unwrap_or(0);
+                if request.len() >= end + 4 + length {
+                    return text.into_owned();
+                }
+            }
+        }
+    }
+}
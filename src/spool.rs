@@ -0,0 +1,314 @@
+use crate::Transaction;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// Configuration for the on-disk spool, where the batches that could not be delivered are
+/// stored until the agent is started the next time. While the Data API is unreachable, or the
+/// queue is nearly full, the waiting batches are also moved there and replayed later.
+#[derive(Deserialize, Debug, Clone)]
+pub struct SpoolConfig {
+    /// The directory for the spool files. It is created if it does not exist.
+    pub directory: PathBuf,
+
+    /// The maximum total size of the spool files, in bytes. The oldest files are removed to make room for new ones.
+    #[serde(default = "default_max_size", alias = "maxSize")]
+    pub max_size: u64,
+
+    /// The maximum age of a spool file, in seconds. Older files are discarded instead of being replayed.
+    #[serde(default = "default_max_age", alias = "maxAge")]
+    pub max_age: u64,
+}
+
+fn default_max_size() -> u64 {
+    100 * 1024 * 1024
+}
+
+fn default_max_age() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+const SPOOL_FILE_EXTENSION: &str = "json";
+
+/// The content of a spool file. The `Transaction` IDs are stored separately, because they are
+/// not part of the serialized `Transaction`s.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SpoolFile<'a> {
+    idempotency_key: &'a str,
+    transactions: &'a [Transaction],
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OwnedSpoolFile {
+    idempotency_key: String,
+    transactions: Vec<Transaction>,
+}
+
+/// A batch read back from the spool.
+pub(crate) struct SpooledBatch {
+    pub(crate) path: PathBuf,
+    pub(crate) idempotency_key: String,
+    pub(crate) transactions: Vec<Transaction>,
+}
+
+/// Stores the undelivered batches as files named after their creation time, so that they can
+/// be replayed in the same order later.
+pub(crate) struct Spool {
+    config: SpoolConfig,
+    sequence: u64,
+}
+
+impl Spool {
+    /// Opens the spool, creating the spool directory if needed.
+    pub(crate) fn open(config: SpoolConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        Ok(Spool {
+            config,
+            sequence: 0,
+        })
+    }
+
+    /// Writes the [`Transaction`](struct.Transaction.html)s into a new spool file, along with the
+    /// idempotency key of their batch.
+    pub(crate) fn store(
+        &mut self,
+        idempotency_key: &str,
+        transactions: &[Transaction],
+    ) -> io::Result<PathBuf> {
+        let content = serde_json::to_vec(&SpoolFile {
+            idempotency_key,
+            transactions,
+        })?;
+        self.make_room(content.len() as u64)?;
+
+        let millis = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        self.sequence += 1;
+        let name = format!("{:020}-{:010}", millis, self.sequence);
+
+        // Write to a temporary file first, so that a crash never leaves a partial spool file behind
+        let tmp = self.config.directory.join(format!("{}.tmp", name));
+        let path = self
+            .config
+            .directory
+            .join(name)
+            .with_extension(SPOOL_FILE_EXTENSION);
+        fs::write(&tmp, content)?;
+        fs::rename(&tmp, &path)?;
+
+        Ok(path)
+    }
+
+    /// Reads the spooled batch in the file, or returns `None` if it can not be read. A file older
+    /// than `max_age` is removed instead.
+    ///
+    /// The file is left in place; it is removed with `remove` once its batch has been delivered.
+    pub(crate) fn load(&self, path: &Path) -> Option<SpooledBatch> {
+        let max_age = Duration::from_secs(self.config.max_age);
+        let expired = fs::metadata(path)
+            .and_then(|metadata| metadata.modified())
+            .map(|modified| modified.elapsed().is_ok_and(|age| age > max_age));
+        match expired {
+            Ok(false) => {}
+            Ok(true) => {
+                println!(
+                    "Aino error: Discarding expired spool file {}",
+                    path.display()
+                );
+                remove(path);
+                return None;
+            }
+            // Discarded to make room for newer batches in the meantime
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                println!(
+                    "Aino error: Failed to read spool file {}: {}",
+                    path.display(),
+                    e
+                );
+                return None;
+            }
+        }
+
+        match fs::read(path)
+            .map_err(|e| e.to_string())
+            .and_then(|content| serde_json::from_slice(&content).map_err(|e| e.to_string()))
+        {
+            Ok(OwnedSpoolFile {
+                idempotency_key,
+                transactions,
+            }) => Some(SpooledBatch {
+                path: path.to_path_buf(),
+                idempotency_key,
+                transactions,
+            }),
+            Err(e) => {
+                println!(
+                    "Aino error: Failed to read spool file {}: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// Removes the oldest spool files until `size` more bytes fit in the spool.
+    fn make_room(&self, size: u64) -> io::Result<()> {
+        if size > self.config.max_size {
+            return Err(io::Error::other(
+                "batch is larger than the maximum spool size",
+            ));
+        }
+
+        let mut files: Vec<(PathBuf, u64)> = self
+            .files()?
+            .into_iter()
+            .map(|path| {
+                let len = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+                (path, len)
+            })
+            .collect();
+        let mut total: u64 = files.iter().map(|(_, len)| len).sum();
+
+        files.reverse();
+        while total + size > self.config.max_size {
+            match files.pop() {
+                Some((path, len)) => {
+                    println!(
+                        "Aino error: Spool is full, discarding oldest spool file {}",
+                        path.display()
+                    );
+                    remove(&path);
+                    total -= len;
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Lists the spool files, oldest first.
+    pub(crate) fn files(&self) -> io::Result<Vec<PathBuf>> {
+        let mut files: Vec<PathBuf> = fs::read_dir(&self.config.directory)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| {
+                path.is_file()
+                    && path.extension().and_then(|e| e.to_str()) == Some(SPOOL_FILE_EXTENSION)
+            })
+            .collect();
+        files.sort();
+        Ok(files)
+    }
+}
+
+/// Removes a spool file whose batch has been dealt with.
+pub(crate) fn remove(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            println!(
+                "Aino error: Failed to remove spool file {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Status;
+    use std::env;
+
+    fn create_spool(name: &str, max_size: u64) -> Spool {
+        let directory = env::temp_dir().join(format!("aino-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        Spool::open(SpoolConfig {
+            directory,
+            max_size,
+            max_age: default_max_age(),
+        })
+        .unwrap()
+    }
+
+    fn load_all(spool: &Spool) -> Vec<SpooledBatch> {
+        let files = spool.files().unwrap();
+        files.iter().filter_map(|path| spool.load(path)).collect()
+    }
+
+    fn create_trx(flow_id: &str) -> Transaction {
+        Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            flow_id.to_string(),
+            "integration_segment".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_store_and_load_in_order() {
+        let mut spool = create_spool("order", default_max_size());
+        let first = create_trx("1");
+        spool
+            .store("key-1", &[first.clone(), create_trx("2")])
+            .unwrap();
+        spool.store("key-2", &[create_trx("3")]).unwrap();
+
+        let batches = load_all(&spool);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].idempotency_key, "key-1");
+        assert_eq!(batches[0].transactions.len(), 2);
+        assert_eq!(batches[0].transactions[0].flow_id, "1");
+        assert_eq!(batches[0].transactions[0].id, first.id);
+        assert_eq!(batches[1].idempotency_key, "key-2");
+        assert_eq!(batches[1].transactions[0].flow_id, "3");
+
+        remove(&batches[0].path);
+        assert_eq!(load_all(&spool).len(), 1);
+        fs::remove_dir_all(&spool.config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_store_discards_oldest_when_full() {
+        let mut spool = create_spool("full", default_max_size());
+        let path = spool.store("key", &[create_trx("1")]).unwrap();
+        spool.config.max_size = fs::metadata(path).unwrap().len() * 2;
+        spool.store("key", &[create_trx("2")]).unwrap();
+        spool.store("key", &[create_trx("3")]).unwrap();
+
+        let batches = load_all(&spool);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].transactions[0].flow_id, "2");
+        assert_eq!(batches[1].transactions[0].flow_id, "3");
+        fs::remove_dir_all(&spool.config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_store_too_large_batch() {
+        let mut spool = create_spool("large", 10);
+        assert!(spool.store("key", &[create_trx("1")]).is_err());
+        fs::remove_dir_all(&spool.config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_discards_expired() {
+        let mut spool = create_spool("expired", default_max_size());
+        spool.config.max_age = 0;
+        spool.store("key", &[create_trx("1")]).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert!(load_all(&spool).is_empty());
+        assert!(spool.files().unwrap().is_empty());
+        fs::remove_dir_all(&spool.config.directory).unwrap();
+    }
+}
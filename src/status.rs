@@ -1,7 +1,7 @@
 use strum_macros::{Display, EnumString};
 
 /// An enumeration of the different `Status` values.
-#[derive(Serialize, Clone, Copy, EnumString, Display, Debug)]
+#[derive(Serialize, Deserialize, Clone, Copy, EnumString, Display, Debug)]
 #[serde(rename_all = "camelCase")]
 pub enum Status {
     /// Indicates a successful [`Transaction`](struct.Transaction.html).
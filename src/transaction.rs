@@ -1,9 +1,11 @@
 use crate::status::Status;
 use std::fmt;
+use uuid::Uuid;
 
 /// A log entry for a single `Transaction` between two applications.
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
+#[non_exhaustive]
 pub struct Transaction {
     /// The name of originating application
     pub from: String,
@@ -41,10 +43,69 @@ pub struct Transaction {
     /// All metadata related to this `Transaction` (optional)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub metadata: Option<Vec<TransactionMetadata>>,
+
+    /// A unique ID generated for the `Transaction` when it is constructed. It can be used to
+    /// correlate the `Transaction` with delivery receipts and dead letters, but it is not sent to `Aino.io`.
+    #[serde(default = "new_transaction_id")]
+    pub id: String,
+
+    /// Sends the `Transaction` in the priority lane right away instead of waiting for the send interval.
+    /// `Transaction`s with [`Status::Failure`](enum.Status.html#variant.Failure) always go to the
+    /// priority lane. It is not sent to `Aino.io`.
+    #[serde(skip_serializing, default)]
+    pub urgent: bool,
+
+    /// The key the agent reports the delivery of a tracked `Transaction` with. Unlike `id`, it is
+    /// not shared by clones of the `Transaction` that are added separately.
+    #[serde(skip)]
+    pub(crate) delivery_key: Option<u64>,
+}
+
+fn new_transaction_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// A `Transaction` as it is sent to `Aino.io`, without the fields only the agent uses.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct WireTransaction<'a> {
+    from: &'a str,
+    to: &'a str,
+    status: &'a Status,
+    timestamp: u128,
+    operation: &'a str,
+    integration_segment: &'a str,
+    flow_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_type: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ids: Option<&'a [TransactionId]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a [TransactionMetadata]>,
+}
+
+impl<'a> From<&'a Transaction> for WireTransaction<'a> {
+    fn from(transaction: &'a Transaction) -> Self {
+        WireTransaction {
+            from: &transaction.from,
+            to: &transaction.to,
+            status: &transaction.status,
+            timestamp: transaction.timestamp,
+            operation: &transaction.operation,
+            integration_segment: &transaction.integration_segment,
+            flow_id: &transaction.flow_id,
+            payload_type: transaction.payload_type.as_deref(),
+            message: transaction.message.as_deref(),
+            ids: transaction.ids.as_deref(),
+            metadata: transaction.metadata.as_deref(),
+        }
+    }
 }
 
 /// Container for IDs of a single type.
-#[derive(Serialize, Clone, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TransactionId {
     /// The type of the ID.
@@ -55,7 +116,7 @@ pub struct TransactionId {
 }
 
 /// A name/value pair for generic metadata.
-#[derive(Serialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct TransactionMetadata {
     /// The name of the metadata.
@@ -88,6 +149,9 @@ impl Transaction {
             message: None,
             ids: None,
             metadata: None,
+            id: new_transaction_id(),
+            urgent: false,
+            delivery_key: None,
         }
     }
 
@@ -101,6 +165,17 @@ impl Transaction {
         self
     }
 
+    /// Marks the `Transaction` urgent, so that it is sent in the priority lane
+    pub fn mark_urgent(&mut self) -> &mut Self {
+        self.urgent = true;
+        self
+    }
+
+    /// Returns `true` if the `Transaction` is sent in the priority lane
+    pub fn is_urgent(&self) -> bool {
+        self.urgent || matches!(self.status, Status::Failure)
+    }
+
     /// Add an ID to the `Transaction`
     pub fn add_id(&mut self, id: TransactionId) -> &mut Self {
         match &mut self.ids {
@@ -172,11 +247,11 @@ mod tests {
             "flow_id".to_string(),
             "integration_segment".to_string(),
         );
-        assert_eq!(trx.metadata.is_none(), true);
+        assert!(trx.metadata.is_none());
 
         let metadata = TransactionMetadata::new("name".to_string(), "value".to_string());
         trx.add_metadata(metadata);
-        assert_eq!(trx.metadata.is_some(), true);
+        assert!(trx.metadata.is_some());
     }
 
     #[test]
@@ -193,14 +268,80 @@ mod tests {
             "flow_id".to_string(),
             "integration_segment".to_string(),
         );
-        assert_eq!(trx.ids.is_none(), true);
+        assert!(trx.ids.is_none());
 
         let id = TransactionId::new("id_type".to_string(), vec!["value".to_string()]);
         trx.add_id(id);
-        assert_eq!(trx.ids.is_some(), true);
+        assert!(trx.ids.is_some());
         if let Some(ids) = &trx.ids {
             assert_eq!(ids[0].id_type, "id_type".to_string());
             assert_eq!(ids[0].values[0], "value".to_string());
         }
     }
+
+    #[test]
+    fn test_unique_id() {
+        let create = || {
+            Transaction::new(
+                "from".to_string(),
+                "to".to_string(),
+                "operation".to_string(),
+                Status::Success,
+                1,
+                "flow_id".to_string(),
+                "integration_segment".to_string(),
+            )
+        };
+        let trx = create();
+        assert_ne!(trx.id, create().id);
+        assert_eq!(trx.id, trx.clone().id);
+    }
+
+    #[test]
+    fn test_id_serialized_but_not_sent() {
+        let mut trx = Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            1,
+            "flow_id".to_string(),
+            "integration_segment".to_string(),
+        );
+        trx.message = Some("message".to_string());
+        trx.add_id(TransactionId::new(
+            "id_type".to_string(),
+            vec!["value".to_string()],
+        ));
+
+        let json = serde_json::to_string(&trx).unwrap();
+        let read: Transaction = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.id, trx.id);
+
+        let wire = serde_json::to_value(WireTransaction::from(&trx)).unwrap();
+        let mut expected = serde_json::to_value(&trx).unwrap();
+        expected.as_object_mut().unwrap().remove("id");
+        assert_eq!(wire, expected);
+    }
+
+    #[test]
+    fn test_is_urgent() {
+        let create = |status| {
+            Transaction::new(
+                "from".to_string(),
+                "to".to_string(),
+                "operation".to_string(),
+                status,
+                1,
+                "flow_id".to_string(),
+                "integration_segment".to_string(),
+            )
+        };
+        let mut trx = create(Status::Success);
+        assert!(!trx.is_urgent());
+        trx.mark_urgent();
+        assert!(trx.is_urgent());
+        assert!(!serde_json::to_string(&trx).unwrap().contains("urgent"));
+        assert!(create(Status::Failure).is_urgent());
+    }
 }
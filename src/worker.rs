@@ -0,0 +1,2158 @@
+use crate::aino_config::AinoConfig;
+use crate::buffer::TransactionBuffer;
+use crate::circuit_breaker::{CircuitBreaker, CircuitState};
+use crate::dead_letter::{DeadLetter, DeadLetterStore};
+use crate::ordering::{DeliveryOrder, FlowGate};
+use crate::queue::TransactionQueue;
+use crate::receipt::{DeliveryOutcome, DeliveryReceipt};
+use crate::spool::{self, Spool, SpooledBatch};
+use crate::transaction::WireTransaction;
+use crate::{AinoError, AinoErrorKind, StopOutcome, Transaction};
+use http_client::isahc::IsahcClient;
+use isahc::config::Configurable;
+use serde::Serializer;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::io;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use surf::http::mime;
+use surf::http::other::RetryAfter;
+use surf::http::Headers;
+use surf::{Body, Client};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tokio::time::timeout_at;
+use uuid::Uuid;
+
+pub(crate) enum Msg {
+    /// Stops the worker once everything is sent, or at the given deadline at the latest.
+    Cancel(Option<Instant>),
+    /// A new `Transaction`, along with the sender for its outcome if the caller tracks it.
+    Trx(Box<Transaction>, Option<oneshot::Sender<DeliveryOutcome>>),
+    /// Sends everything received so far, and signals when all of it has a final outcome.
+    Flush(oneshot::Sender<()>),
+    /// The result of the send with the given ID.
+    Sent(u64, SendResult),
+}
+
+pub(crate) enum ThreadMsg {
+    Finished(StopOutcome),
+}
+
+/// What happened to a batch sent to the Data API.
+pub(crate) enum SendResult {
+    Delivered(Option<String>),
+    Failed(AinoError),
+    /// The HTTP status, and how long the Data API asked to wait.
+    Throttled(u16, Option<Duration>),
+    TooLarge,
+    Rejected(u16, String),
+}
+
+#[derive(Deserialize, Debug)]
+struct BatchResponse {
+    batch: String,
+}
+
+#[derive(Serialize)]
+pub(crate) struct BatchRequest {
+    #[serde(serialize_with = "serialize_wire")]
+    transactions: Vec<Transaction>,
+
+    /// Sent with every attempt of the batch, so that the Data API can discard duplicates.
+    #[serde(skip)]
+    idempotency_key: String,
+
+    /// The spool file this batch was replayed from, removed once the batch is delivered.
+    #[serde(skip)]
+    spool_file: Option<PathBuf>,
+
+    /// The sequence number of the first `Transaction` in the batch, which tells the flushes
+    /// whether they have to wait for the batch.
+    #[serde(skip)]
+    first_seq: u64,
+}
+
+impl BatchRequest {
+    fn new(transactions: Vec<Transaction>, first_seq: u64) -> Self {
+        BatchRequest {
+            transactions,
+            idempotency_key: Uuid::new_v4().to_string(),
+            spool_file: None,
+            first_seq,
+        }
+    }
+}
+
+/// Serializes the `Transaction`s of a batch without the fields only the agent uses.
+fn serialize_wire<S: Serializer>(
+    transactions: &[Transaction],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(transactions.iter().map(WireTransaction::from))
+}
+
+/// The Data API endpoint the batches are sent to.
+#[derive(Clone)]
+struct Endpoint {
+    client: Client,
+    url: String,
+    authorization: String,
+}
+
+impl Endpoint {
+    fn new(config: &AinoConfig) -> Result<Self, Box<dyn Error>> {
+        let client = isahc::HttpClient::builder()
+            .connect_timeout(Duration::from_millis(config.connect_timeout as u64))
+            .timeout(Duration::from_millis(config.request_timeout as u64))
+            .build()?;
+        Ok(Endpoint {
+            client: Client::with_http_client(IsahcClient::from_client(client)),
+            url: config.url.clone(),
+            authorization: format!("apikey {}", config.api_key),
+        })
+    }
+}
+
+/// A failed batch waiting to be resent.
+struct PendingRetry {
+    batch: BatchRequest,
+    attempts: u32,
+    due: Instant,
+}
+
+/// A batch that is being sent. `attempts` is the number of times it has been sent before.
+struct InFlight {
+    batch: BatchRequest,
+    attempts: u32,
+}
+
+/// A flush waiting for the `Transaction`s received before it.
+struct PendingFlush {
+    /// The sequence number of the first `Transaction` received after the flush.
+    seq: u64,
+    done: oneshot::Sender<()>,
+}
+
+/// How a response from the Data API should be handled.
+#[derive(Debug, PartialEq)]
+enum ResponseClass {
+    Success,
+    Retryable,
+    /// The URL or API key is wrong, which is fixed in the configuration rather than in the batch.
+    Misconfigured,
+    Throttled,
+    TooLarge,
+    Permanent,
+}
+
+/// Owns the buffered `Transaction`s and the batches being sent or waiting to be resent, and
+/// decides when to send them.
+///
+/// The worker sleeps until a message arrives or the next batch or retry is due, so an idle agent
+/// does not use any CPU.
+pub(crate) struct Worker {
+    /// The name of the destination in `AinoConfig::destinations`, or `None` for the top-level `url`.
+    destination: Option<String>,
+    config: AinoConfig,
+    endpoint: Endpoint,
+    receiver: UnboundedReceiver<Msg>,
+    feedback: UnboundedSender<Msg>,
+    finished: mpsc::Sender<ThreadMsg>,
+    breaker: CircuitBreaker,
+    spool: Option<Spool>,
+    dead_letters: DeadLetterStore,
+    queue: Arc<TransactionQueue>,
+    /// The routine `Transaction`s, sent once per send interval or when a batch is full.
+    buffer: TransactionBuffer,
+    /// The urgent `Transaction`s, sent right away.
+    priority: TransactionBuffer,
+    retries: Vec<PendingRetry>,
+    /// The spool files left by the previous run that have not been replayed yet, oldest first.
+    replay: VecDeque<PathBuf>,
+    /// The next batch from the spool, waiting for room in the queue.
+    next_replay: Option<SpooledBatch>,
+    /// The delivery keys of the tracked `Transaction`s in the batches spilled into the spool
+    /// during this run, by spool file.
+    spilled: HashMap<PathBuf, Vec<Option<u64>>>,
+    in_flight: BTreeMap<u64, InFlight>,
+    next_send_id: u64,
+    next_seq: u64,
+    flushes: Vec<PendingFlush>,
+    /// The senders for the outcomes of the tracked `Transaction`s, by the sequence number they
+    /// were received in.
+    outcomes: HashMap<u64, oneshot::Sender<DeliveryOutcome>>,
+    shutting_down: bool,
+    stop_deadline: Option<Instant>,
+    interval_start: Instant,
+    paused_until: Option<Instant>,
+    reported_drops: u64,
+    delivered: u64,
+    spooled: u64,
+    undelivered: Vec<Transaction>,
+}
+
+impl Worker {
+    /// Constructs a new `Worker`, starting to replay the batches left in the spool by the previous run.
+    pub(crate) fn new(
+        destination: Option<String>,
+        config: AinoConfig,
+        receiver: UnboundedReceiver<Msg>,
+        feedback: UnboundedSender<Msg>,
+        finished: mpsc::Sender<ThreadMsg>,
+        circuit_state: Arc<Mutex<CircuitState>>,
+        queue: Arc<TransactionQueue>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let endpoint = Endpoint::new(&config)?;
+        let breaker = CircuitBreaker::new(config.circuit_breaker.clone(), circuit_state);
+        let spool = match &config.spool {
+            Some(spool_config) => Some(Spool::open(spool_config.clone())?),
+            None => None,
+        };
+        let replay = match &spool {
+            Some(spool) => spool.files()?.into(),
+            None => VecDeque::new(),
+        };
+        let dead_letters = DeadLetterStore::open(
+            config.dead_letter.as_ref(),
+            config.dead_letter_handler.clone(),
+        )?;
+
+        let mut worker = Worker {
+            destination,
+            config,
+            endpoint,
+            receiver,
+            feedback,
+            finished,
+            breaker,
+            spool,
+            dead_letters,
+            queue,
+            buffer: TransactionBuffer::new(),
+            priority: TransactionBuffer::new(),
+            retries: Vec::new(),
+            replay,
+            next_replay: None,
+            spilled: HashMap::new(),
+            in_flight: BTreeMap::new(),
+            next_send_id: 0,
+            // The batches replayed from the spool come before any new `Transaction`
+            next_seq: 1,
+            flushes: Vec::new(),
+            outcomes: HashMap::new(),
+            shutting_down: false,
+            stop_deadline: None,
+            interval_start: Instant::now(),
+            paused_until: None,
+            reported_drops: 0,
+            delivered: 0,
+            spooled: 0,
+            undelivered: Vec::new(),
+        };
+        worker.replay_spool();
+        Ok(worker)
+    }
+
+    /// Runs until the agent is stopped and every batch has been delivered or given up on, or
+    /// until the stop deadline has passed.
+    pub(crate) async fn run(mut self) {
+        loop {
+            let deadline = self
+                .next_deadline(Instant::now())
+                .into_iter()
+                .chain(self.stop_deadline)
+                .min();
+            let received = match deadline {
+                Some(deadline) => timeout_at(deadline.into(), self.receiver.recv()).await.ok(),
+                None => Some(self.receiver.recv().await),
+            };
+
+            match received {
+                Some(Some(msg)) => {
+                    self.handle_message(msg);
+                    while let Ok(msg) = self.receiver.try_recv() {
+                        self.handle_message(msg);
+                    }
+                }
+                Some(None) => self.shutting_down = true,
+                // The deadline passed without any messages
+                None => {}
+            }
+
+            self.replay_spool();
+            let now = Instant::now();
+            self.dispatch(now);
+            self.spill(now);
+            if self.stop_deadline.is_some_and(|deadline| deadline <= now) {
+                self.abandon();
+            }
+            self.complete_flushes();
+
+            if self.is_finished() {
+                let outcome = StopOutcome {
+                    delivered: self.delivered,
+                    spooled: self.spooled,
+                    undelivered: std::mem::take(&mut self.undelivered),
+                };
+                self.finished
+                    .send(ThreadMsg::Finished(outcome))
+                    .expect("Failed to send Finished message back to main thread.");
+
+                break;
+            }
+        }
+    }
+
+    fn handle_message(&mut self, msg: Msg) {
+        match msg {
+            Msg::Cancel(deadline) => {
+                self.shutting_down = true;
+                self.stop_replaying();
+                self.stop_deadline = self.stop_deadline.into_iter().chain(deadline).min();
+            }
+            Msg::Trx(mut transaction, outcome) => {
+                // Clones of a tracked `Transaction` share its ID, so each addition gets its own key
+                transaction.delivery_key = outcome.map(|outcome| {
+                    self.outcomes.insert(self.next_seq, outcome);
+                    self.next_seq
+                });
+                if transaction.is_urgent() {
+                    // The earlier routine `Transaction`s of the flow go ahead of the urgent one
+                    if self.config.ordering == DeliveryOrder::PerFlow {
+                        self.buffer
+                            .move_flow(&transaction.flow_id, &mut self.priority);
+                    }
+                    self.priority.push_back(*transaction, self.next_seq);
+                } else {
+                    self.buffer.push_back(*transaction, self.next_seq);
+                }
+                self.next_seq += 1;
+                let dropped = drop_oldest(&self.queue, &mut self.buffer, &mut self.priority);
+                self.resolve(
+                    &dropped,
+                    DeliveryOutcome::Dropped {
+                        reason: AinoErrorKind::QueueFull,
+                    },
+                );
+            }
+            Msg::Flush(done) => self.flushes.push(PendingFlush {
+                seq: self.next_seq,
+                done,
+            }),
+            Msg::Sent(id, result) => {
+                // The batch is no longer tracked if it was handed back at the stop deadline
+                if let Some(InFlight { batch, attempts }) = self.in_flight.remove(&id) {
+                    self.handle_result(batch, attempts, result);
+                }
+            }
+        }
+    }
+
+    fn handle_result(&mut self, batch: BatchRequest, attempts: u32, result: SendResult) {
+        match result {
+            SendResult::Delivered(batch_id) => {
+                self.breaker.on_success();
+                if let Some(path) = &batch.spool_file {
+                    spool::remove(path);
+                }
+                self.queue.release(batch.transactions.len());
+                self.delivered += batch.transactions.len() as u64;
+                self.resolve(
+                    &batch.transactions,
+                    DeliveryOutcome::Delivered {
+                        batch_id: batch_id.clone(),
+                    },
+                );
+                if let Some(handler) = &self.config.receipt_handler {
+                    let mut receipt = DeliveryReceipt::new(batch_id, batch.transactions);
+                    receipt.destination = self.destination.clone();
+                    handler.handle(&receipt);
+                }
+            }
+            SendResult::Failed(error) => {
+                println!("{}", error);
+                self.breaker.on_failure(Instant::now());
+                if !self.config.retry.can_retry(attempts + 1) {
+                    self.resolve(
+                        &batch.transactions,
+                        DeliveryOutcome::Dropped {
+                            reason: error.kind(),
+                        },
+                    );
+                }
+                schedule_retry(
+                    &mut self.retries,
+                    &self.config,
+                    self.spool.as_mut(),
+                    &self.queue,
+                    batch,
+                    attempts + 1,
+                    Instant::now() + self.config.retry.backoff(attempts + 1),
+                );
+            }
+            SendResult::Throttled(status, retry_after) => {
+                // A 503 without `Retry-After` usually comes from a load balancer in front of an
+                // unreachable Data API
+                match (status, retry_after) {
+                    (503, None) => self.breaker.on_failure(Instant::now()),
+                    (503, Some(_)) => self.breaker.release(),
+                    _ => self.breaker.on_success(),
+                }
+                // Throttling counts as an attempt, so a Data API that keeps throttling is
+                // eventually given up on like any failing one
+                let delay = retry_after.unwrap_or_else(|| self.config.retry.backoff(attempts + 1));
+                let until = pause(&mut self.paused_until, delay);
+                if !self.config.retry.can_retry(attempts + 1) {
+                    self.resolve(
+                        &batch.transactions,
+                        DeliveryOutcome::Dropped {
+                            reason: AinoErrorKind::Other,
+                        },
+                    );
+                }
+                schedule_retry(
+                    &mut self.retries,
+                    &self.config,
+                    self.spool.as_mut(),
+                    &self.queue,
+                    batch,
+                    attempts + 1,
+                    until,
+                );
+            }
+            SendResult::TooLarge => {
+                self.breaker.on_success();
+                split_batch(&mut self.retries, batch, attempts);
+            }
+            SendResult::Rejected(status, response) => {
+                self.breaker.on_success();
+                if let Some(path) = &batch.spool_file {
+                    spool::remove(path);
+                }
+                self.queue.release(batch.transactions.len());
+                self.resolve(
+                    &batch.transactions,
+                    DeliveryOutcome::Rejected {
+                        status,
+                        reason: response.clone(),
+                    },
+                );
+                let mut letter = DeadLetter::new(status, response, batch.transactions);
+                letter.destination = self.destination.clone();
+                self.dead_letters.store(&letter);
+            }
+        }
+    }
+
+    /// Reports the outcome of the tracked `Transaction`s among `transactions`.
+    fn resolve(&mut self, transactions: &[Transaction], outcome: DeliveryOutcome) {
+        if self.outcomes.is_empty() {
+            return;
+        }
+        for transaction in transactions {
+            let sender = transaction
+                .delivery_key
+                .and_then(|key| self.outcomes.remove(&key));
+            if let Some(sender) = sender {
+                let _ = sender.send(outcome.clone());
+            }
+        }
+    }
+
+    /// Replays the batches left in the spool by the previous run one at a time, as room frees
+    /// up in the queue, so that a large spool neither fills the memory nor crowds out the new
+    /// `Transaction`s.
+    fn replay_spool(&mut self) {
+        let Some(spool) = &self.spool else {
+            return;
+        };
+        // Nothing is sent while the circuit is open, so the batches are left in the spool meanwhile
+        if self
+            .breaker
+            .reopens_at()
+            .is_some_and(|at| Instant::now() < at)
+        {
+            return;
+        }
+        while self.next_replay.is_some() || !self.replay.is_empty() {
+            let mut spooled = match self.next_replay.take() {
+                Some(spooled) => spooled,
+                None => match self.replay.pop_front().and_then(|path| spool.load(&path)) {
+                    Some(spooled) => spooled,
+                    None => continue,
+                },
+            };
+            if !self.queue.try_reserve_replayed(spooled.transactions.len()) {
+                self.next_replay = Some(spooled);
+                return;
+            }
+            if let Some(keys) = self.spilled.remove(&spooled.path) {
+                self.spooled -= keys.len() as u64;
+                for (transaction, key) in spooled.transactions.iter_mut().zip(keys) {
+                    transaction.delivery_key = key;
+                }
+            }
+            self.retries.push(replayed_retry(spooled));
+        }
+    }
+
+    /// Stops replaying the spool when the agent is stopped. The files not replayed yet are safe on
+    /// disk, so they are left for the next run instead of holding up the stop.
+    fn stop_replaying(&mut self) {
+        self.replay.clear();
+        self.next_replay = None;
+        self.spilled.clear();
+    }
+
+    /// Moves the batches waiting to be sent into the spool while the circuit is open, or while
+    /// the queue is nearly full, and frees their room in the queue. This way a long outage
+    /// neither fills the queue, which would drop new `Transaction`s, nor leaves them only in
+    /// memory. The spilled batches are replayed like the ones left by the previous run, or left
+    /// for the next run if the agent is stopping.
+    fn spill(&mut self, now: Instant) {
+        if self.spool.is_none() {
+            return;
+        }
+        let open = self.breaker.reopens_at().is_some_and(|at| now < at);
+        let nearly_full = !self.shutting_down && self.queue.is_nearly_full();
+        if !open && !nearly_full {
+            return;
+        }
+
+        let mut batches: Vec<PendingRetry> = self.retries.drain(..).collect();
+        // While the circuit is open, the buffered `Transaction`s are spilled when they would
+        // otherwise have been sent
+        if nearly_full
+            || self.shutting_down
+            || can_send_batch(&self.interval_start, &self.config, &self.priority)
+            || can_send_batch(&self.interval_start, &self.config, &self.buffer)
+        {
+            for buffer in [&mut self.priority, &mut self.buffer] {
+                for batch in drain_batches(buffer, &self.config) {
+                    batches.push(PendingRetry {
+                        batch,
+                        attempts: 0,
+                        due: now,
+                    });
+                }
+            }
+            self.interval_start = now;
+        }
+
+        let mut failed = Vec::new();
+        for retry in batches {
+            let count = retry.batch.transactions.len();
+            let stored = match (&retry.batch.spool_file, self.spool.as_mut()) {
+                (Some(path), _) => Ok(path.clone()),
+                (None, Some(spool)) => {
+                    spool.store(&retry.batch.idempotency_key, &retry.batch.transactions)
+                }
+                (None, None) => Err(io::Error::other("no spool is configured")),
+            };
+            let path = match stored {
+                Ok(path) => path,
+                Err(e) => {
+                    println!(
+                        "Aino error: Failed to spool a batch of {} transactions: {}",
+                        count, e
+                    );
+                    failed.push(retry);
+                    continue;
+                }
+            };
+
+            self.queue.release(count);
+            self.spooled += count as u64;
+            if !self.shutting_down {
+                let keys = retry
+                    .batch
+                    .transactions
+                    .iter()
+                    .map(|transaction| transaction.delivery_key)
+                    .collect();
+                self.spilled.insert(path.clone(), keys);
+                self.replay.push_back(path);
+            }
+        }
+        // The batches that could not be spooled stay in memory and are sent as usual
+        self.retries = failed;
+    }
+
+    /// Sends the retries that are due and the batches that are ready.
+    fn dispatch(&mut self, now: Instant) {
+        // While the Data API is throttling, the transactions are only buffered
+        if self.paused_until.is_some_and(|until| now < until) {
+            return;
+        }
+
+        // While the circuit is open or too many batches are being sent, the batches stay in the
+        // buffer and retry queue, or in the spool. With per flow ordering, the retries go out
+        // oldest first and only once the earlier batches of their flows have been delivered.
+        let mut gate = self.flow_gate();
+        for retry in take_due_retries(&mut self.retries, now, &mut gate) {
+            if !self.can_send_more() || !self.breaker.try_acquire(now) {
+                self.retries.push(retry);
+                continue;
+            }
+            self.spawn_send(retry.batch, retry.attempts);
+        }
+
+        // The priority lane goes ahead of the routine `Transaction`s
+        while self
+            .priority
+            .iter()
+            .any(|transaction| gate.admits(transaction))
+            && self.can_send_more()
+            && self.breaker.try_acquire(now)
+        {
+            let batch = create_batch_request(&mut self.priority, &self.config, &gate);
+            gate.block(&batch.transactions);
+            report_drops(&self.queue, &mut self.reported_drops);
+            self.spawn_send(batch, 0);
+        }
+        gate.block(self.priority.iter());
+
+        // When shutting down or flushing, the buffer is sent without waiting for the interval
+        while (self.must_send_buffer()
+            || can_send_batch(&self.interval_start, &self.config, &self.buffer))
+            && self
+                .buffer
+                .iter()
+                .any(|transaction| gate.admits(transaction))
+            && self.can_send_more()
+            && self.breaker.try_acquire(now)
+        {
+            let batch = create_batch_request(&mut self.buffer, &self.config, &gate);
+            gate.block(&batch.transactions);
+            report_drops(&self.queue, &mut self.reported_drops);
+            self.interval_start = now;
+            self.spawn_send(batch, 0);
+        }
+    }
+
+    /// A gate blocking the flows of the batches being sent, when the order is per flow.
+    fn flow_gate(&self) -> FlowGate {
+        let mut gate = FlowGate::new(self.config.ordering);
+        for in_flight in self.in_flight.values() {
+            gate.block(&in_flight.batch.transactions);
+        }
+        gate
+    }
+
+    fn spawn_send(&mut self, batch: BatchRequest, attempts: u32) {
+        let body = match serde_json::to_vec(&batch) {
+            Ok(body) => body,
+            Err(e) => {
+                // The batch can not be serialized, so resending it would not help
+                println!("Aino error: {}", e);
+                self.breaker.release();
+                if let Some(path) = &batch.spool_file {
+                    spool::remove(path);
+                }
+                self.queue.release(batch.transactions.len());
+                self.resolve(
+                    &batch.transactions,
+                    DeliveryOutcome::Dropped {
+                        reason: AinoErrorKind::Other,
+                    },
+                );
+                return;
+            }
+        };
+
+        let id = self.next_send_id;
+        self.next_send_id += 1;
+        tokio::spawn(send_batch(
+            self.endpoint.clone(),
+            id,
+            body,
+            batch.idempotency_key.clone(),
+            batch.transactions.len() > 1,
+            self.feedback.clone(),
+        ));
+        self.in_flight.insert(id, InFlight { batch, attempts });
+    }
+
+    /// Gives up on everything that has not been delivered by the stop deadline. With a spool, it
+    /// is stored there for the next run, and the batches replayed from the spool stay in their
+    /// files. Everything else is handed back to the caller. Late results of the abandoned sends
+    /// are ignored.
+    fn abandon(&mut self) {
+        let mut batches: Vec<BatchRequest> = std::mem::take(&mut self.in_flight)
+            .into_values()
+            .map(|in_flight| in_flight.batch)
+            .collect();
+        self.retries.sort_by_key(|retry| retry.due);
+        batches.extend(self.retries.drain(..).map(|retry| retry.batch));
+        self.breaker.release();
+        for buffer in [&mut self.priority, &mut self.buffer] {
+            batches.extend(drain_batches(buffer, &self.config));
+        }
+
+        let mut count = 0;
+        for batch in batches {
+            count += batch.transactions.len();
+            self.hand_over(batch);
+        }
+        self.stop_replaying();
+        self.queue.release(count);
+        // The spooled and handed back `Transaction`s are reported as dropped
+        self.outcomes.clear();
+    }
+
+    /// Stores an abandoned batch in the spool, or hands it back to the caller if there is no
+    /// spool or the batch can not be stored.
+    fn hand_over(&mut self, batch: BatchRequest) {
+        let count = batch.transactions.len() as u64;
+        if batch.spool_file.is_some() {
+            self.spooled += count;
+            return;
+        }
+        if let Some(spool) = &mut self.spool {
+            match spool.store(&batch.idempotency_key, &batch.transactions) {
+                Ok(_) => {
+                    self.spooled += count;
+                    return;
+                }
+                Err(e) => println!(
+                    "Aino error: Failed to spool a batch of {} transactions: {}",
+                    count, e
+                ),
+            }
+        }
+        self.undelivered.extend(batch.transactions);
+    }
+
+    /// Returns `true` if another batch can be sent without exceeding `max_in_flight`.
+    fn can_send_more(&self) -> bool {
+        self.in_flight.len() < self.config.max_in_flight.max(1)
+    }
+
+    /// Returns `true` if the buffer holds routine `Transaction`s that have to be sent right away.
+    fn must_send_buffer(&self) -> bool {
+        match self.buffer.front_seq() {
+            Some(seq) => self.shutting_down || self.flushes.iter().any(|flush| flush.seq > seq),
+            None => false,
+        }
+    }
+
+    /// The sequence number of the oldest `Transaction` without a final outcome.
+    fn oldest_pending_seq(&self) -> Option<u64> {
+        let buffered = self
+            .buffer
+            .front_seq()
+            .into_iter()
+            .chain(self.priority.front_seq());
+        let retried = self.retries.iter().map(|retry| retry.batch.first_seq);
+        let sent = self
+            .in_flight
+            .values()
+            .map(|in_flight| in_flight.batch.first_seq);
+        buffered.chain(retried).chain(sent).min()
+    }
+
+    /// Signals the flushes whose `Transaction`s all have a final outcome.
+    fn complete_flushes(&mut self) {
+        let oldest = self.oldest_pending_seq();
+        let (done, pending) = self
+            .flushes
+            .drain(..)
+            .partition(|flush| oldest.is_none_or(|oldest| oldest >= flush.seq));
+        self.flushes = pending;
+        for flush in done {
+            let _ = flush.done.send(());
+        }
+    }
+
+    /// The time the worker has to wake up even if no messages arrive, or `None` if it only needs
+    /// to wait for messages.
+    fn next_deadline(&self, now: Instant) -> Option<Instant> {
+        if let Some(until) = self.paused_until.filter(|until| now < *until) {
+            return Some(until);
+        }
+
+        // The probe batch, or one of the batches being sent, has to finish before anything
+        // else can be sent
+        if self.breaker.is_probing() || !self.can_send_more() {
+            return None;
+        }
+        if let Some(reopens_at) = self.breaker.reopens_at().filter(|at| now < *at) {
+            // Meanwhile the buffered `Transaction`s are spilled into the spool once per interval
+            let spill_due = (self.spool.is_some()
+                && !(self.buffer.is_empty() && self.priority.is_empty()))
+            .then(|| self.interval_start + Duration::from_millis(self.config.send_interval as u64));
+            return spill_due.into_iter().chain(Some(reopens_at)).min();
+        }
+
+        // The `Transaction`s waiting for an earlier batch of their flow are sent once that
+        // batch is done, so they do not need a deadline
+        let mut gate = self.flow_gate();
+        let mut retries: Vec<&PendingRetry> = self.retries.iter().collect();
+        if gate.is_enabled() {
+            retries.sort_by_key(|retry| retry.batch.first_seq);
+        }
+        let mut retry_due = None;
+        for retry in retries {
+            if gate.admits_all(&retry.batch.transactions) {
+                retry_due = retry_due.into_iter().chain(Some(retry.due)).min();
+            }
+            gate.block(&retry.batch.transactions);
+        }
+
+        let priority_ready = self
+            .priority
+            .iter()
+            .any(|transaction| gate.admits(transaction));
+        gate.block(self.priority.iter());
+        let buffer_ready = self
+            .buffer
+            .iter()
+            .any(|transaction| gate.admits(transaction));
+        let batch_due = if priority_ready || (buffer_ready && self.must_send_buffer()) {
+            Some(now)
+        } else if buffer_ready {
+            Some(self.interval_start + Duration::from_millis(self.config.send_interval as u64))
+        } else {
+            None
+        };
+
+        batch_due.into_iter().chain(retry_due).min()
+    }
+
+    fn is_finished(&self) -> bool {
+        self.shutting_down
+            && self.buffer.is_empty()
+            && self.priority.is_empty()
+            && self.retries.is_empty()
+            && self.in_flight.is_empty()
+            && self.next_replay.is_none()
+            && self.replay.is_empty()
+    }
+}
+
+/// A batch replayed from the spool, which is sent right away. Its `Transaction`s were added before
+/// any new one.
+fn replayed_retry(spooled: SpooledBatch) -> PendingRetry {
+    PendingRetry {
+        batch: BatchRequest {
+            transactions: spooled.transactions,
+            idempotency_key: spooled.idempotency_key,
+            spool_file: Some(spooled.path),
+            first_seq: 0,
+        },
+        attempts: 0,
+        due: Instant::now(),
+    }
+}
+
+/// Schedules a batch that has been sent `attempts` times to be resent at `due`, or gives up on it
+/// once it has used up its attempts.
+fn schedule_retry(
+    retries: &mut Vec<PendingRetry>,
+    config: &AinoConfig,
+    spool: Option<&mut Spool>,
+    queue: &TransactionQueue,
+    batch: BatchRequest,
+    attempts: u32,
+    due: Instant,
+) {
+    if !config.retry.can_retry(attempts) {
+        queue.release(batch.transactions.len());
+        give_up(spool, batch, attempts);
+        return;
+    }
+
+    retries.push(PendingRetry {
+        batch,
+        attempts,
+        due,
+    });
+}
+
+/// Stores a batch that could not be delivered into the spool, or drops it if there is no spool.
+/// A batch replayed from the spool is left there for the next run.
+fn give_up(spool: Option<&mut Spool>, batch: BatchRequest, attempts: u32) {
+    if batch.spool_file.is_some() {
+        return;
+    }
+
+    match spool {
+        Some(spool) => {
+            if let Err(e) = spool.store(&batch.idempotency_key, &batch.transactions) {
+                println!(
+                    "Aino error: Failed to spool a batch of {} transactions: {}",
+                    batch.transactions.len(),
+                    e
+                );
+            }
+        }
+        None => println!(
+            "Aino error: Dropping a batch of {} transactions after {} attempts",
+            batch.transactions.len(),
+            attempts
+        ),
+    }
+}
+
+/// Pauses all sending for `delay`, unless it is already paused for longer. Returns the time the
+/// throttled batch can be resent, first of all.
+fn pause(paused_until: &mut Option<Instant>, delay: Duration) -> Instant {
+    let until = Instant::now() + delay;
+    if paused_until.is_none_or(|current| current < until) {
+        println!(
+            "Aino error: Data API is throttling, pausing sending for {} ms",
+            delay.as_millis()
+        );
+        *paused_until = Some(until);
+    }
+    until
+}
+
+/// Splits a batch that was too large for the Data API in two, and sends both halves right away.
+/// The halves are split again until they fit, or until a single `Transaction` is left.
+fn split_batch(retries: &mut Vec<PendingRetry>, mut batch: BatchRequest, attempts: u32) {
+    if let Some(path) = batch.spool_file.take() {
+        // The halves are spooled again separately if they can not be delivered
+        spool::remove(&path);
+    }
+
+    let second_half = batch.transactions.split_off(batch.transactions.len() / 2);
+    let second_seq = batch.first_seq + batch.transactions.len() as u64;
+    let now = Instant::now();
+    for (transactions, first_seq) in [
+        (batch.transactions, batch.first_seq),
+        (second_half, second_seq),
+    ] {
+        retries.push(PendingRetry {
+            batch: BatchRequest::new(transactions, first_seq),
+            attempts,
+            due: now,
+        });
+    }
+}
+
+/// Applies [`OverflowPolicy::DropOldest`](enum.OverflowPolicy.html) by dropping the oldest buffered
+/// `Transaction`s while the queue is over its maximum size. The routine `Transaction`s are dropped
+/// before the urgent ones. Returns the dropped `Transaction`s.
+fn drop_oldest(
+    queue: &TransactionQueue,
+    buffer: &mut TransactionBuffer,
+    priority: &mut TransactionBuffer,
+) -> Vec<Transaction> {
+    let mut dropped = Vec::new();
+    for _ in 0..queue.excess() {
+        match buffer.pop_front().or_else(|| priority.pop_front()) {
+            Some(transaction) => dropped.push(transaction),
+            None => break,
+        }
+        queue.release(1);
+        queue.record_drops(1);
+    }
+    dropped
+}
+
+/// Reports the `Transaction`s dropped since the last report.
+fn report_drops(queue: &TransactionQueue, reported_drops: &mut u64) {
+    let dropped = queue.dropped();
+    if dropped > *reported_drops {
+        println!(
+            "Aino error: Dropped {} transactions because the queue is full",
+            dropped - *reported_drops
+        );
+        *reported_drops = dropped;
+    }
+}
+
+fn can_send_batch(
+    interval_start: &Instant,
+    config: &AinoConfig,
+    buffer: &TransactionBuffer,
+) -> bool {
+    !buffer.is_empty()
+        && (interval_start.elapsed().as_millis() >= config.send_interval as u128
+            || max_batch_size(config) < buffer.len()
+            || config.max_batch_bytes < buffer.bytes())
+}
+
+/// The number of `Transaction`s in a batch. A `max_batch_size` of zero, which can only be set in
+/// code, is taken as one so that every batch carries at least one `Transaction`.
+fn max_batch_size(config: &AinoConfig) -> usize {
+    config.max_batch_size.max(1)
+}
+
+/// Takes the retries that are due and not blocked by an earlier batch of their flow. The flows
+/// of all the retries are blocked in the gate.
+fn take_due_retries(
+    retries: &mut Vec<PendingRetry>,
+    now: Instant,
+    gate: &mut FlowGate,
+) -> Vec<PendingRetry> {
+    if gate.is_enabled() {
+        retries.sort_by_key(|retry| retry.batch.first_seq);
+    }
+    let mut due = Vec::new();
+    for retry in std::mem::take(retries) {
+        let ready = retry.due <= now && gate.admits_all(&retry.batch.transactions);
+        gate.block(&retry.batch.transactions);
+        if ready {
+            due.push(retry);
+        } else {
+            retries.push(retry);
+        }
+    }
+    due
+}
+
+/// Cuts all the buffered `Transaction`s into batches, regardless of the flow order.
+fn drain_batches(buffer: &mut TransactionBuffer, config: &AinoConfig) -> Vec<BatchRequest> {
+    let mut batches = Vec::new();
+    while !buffer.is_empty() {
+        let transactions =
+            buffer.drain_batch(max_batch_size(config), config.max_batch_bytes, |_| true);
+        batches.push(BatchRequest::new(transactions, 0));
+    }
+    batches
+}
+
+/// Cuts the next batch from the buffer, limited by both the number of `Transaction`s and their size.
+/// The `Transaction`s of the flows blocked by the gate stay in the buffer.
+fn create_batch_request(
+    buffer: &mut TransactionBuffer,
+    config: &AinoConfig,
+    gate: &FlowGate,
+) -> BatchRequest {
+    let admits = |transaction: &Transaction| gate.admits(transaction);
+    let first_seq = buffer.first_seq_where(admits).unwrap_or_default();
+    BatchRequest::new(
+        buffer.drain_batch(max_batch_size(config), config.max_batch_bytes, admits),
+        first_seq,
+    )
+}
+
+/// Sends the serialized batch and reports the result back to the worker, which takes care of
+/// resending it if needed. `splittable` tells whether the batch can be split if it is too large.
+async fn send_batch(
+    endpoint: Endpoint,
+    id: u64,
+    body: Vec<u8>,
+    idempotency_key: String,
+    splittable: bool,
+    feedback: UnboundedSender<Msg>,
+) {
+    let mut body = Body::from_bytes(body);
+    body.set_mime(mime::JSON);
+    let req = endpoint
+        .client
+        .post(&endpoint.url)
+        .header("Authorization", endpoint.authorization.as_str())
+        .header("Idempotency-Key", idempotency_key.as_str())
+        .body(body);
+
+    let result = match req.await {
+        Ok(mut res) => {
+            let status = u16::from(res.status());
+            match classify_response(status) {
+                ResponseClass::Success => {
+                    let batch_id = match res.body_json::<BatchResponse>().await {
+                        Ok(response) => Some(response.batch),
+                        Err(e) => {
+                            println!("Aino error: Failed to parse batch response: {}", e);
+                            None
+                        }
+                    };
+                    SendResult::Delivered(batch_id)
+                }
+                ResponseClass::Retryable => SendResult::Failed(AinoError::new(format!(
+                    "Aino error: Data API responded with status {}",
+                    status
+                ))),
+                ResponseClass::Misconfigured => SendResult::Failed(AinoError::new(format!(
+                    "Aino error: Data API responded with status {}, check the url and api_key",
+                    status
+                ))),
+                ResponseClass::Throttled => SendResult::Throttled(status, retry_after(&res)),
+                ResponseClass::TooLarge if splittable => SendResult::TooLarge,
+                // A single `Transaction` that is too large is rejected like any malformed one
+                ResponseClass::TooLarge | ResponseClass::Permanent => {
+                    let body = res.body_string().await.unwrap_or_default();
+                    SendResult::Rejected(status, body)
+                }
+            }
+        }
+        Err(e) if is_timeout(&e) => SendResult::Failed(AinoError::with_kind(
+            AinoErrorKind::Timeout,
+            "Aino error: Request to the Data API timed out".to_string(),
+        )),
+        Err(e) => SendResult::Failed(AinoError::new(format!("Aino error: {}", e))),
+    };
+    let _ = feedback.send(Msg::Sent(id, result));
+}
+
+/// Tells whether the request failed because the connect or request timeout elapsed.
+fn is_timeout(error: &surf::Error) -> bool {
+    match error.downcast_ref::<isahc::Error>() {
+        Some(error) => matches!(error, isahc::Error::Timeout),
+        None => error
+            .downcast_ref::<io::Error>()
+            .is_some_and(|error| error.kind() == io::ErrorKind::TimedOut),
+    }
+}
+
+/// Server errors and timeouts are worth retrying, and so are the errors caused by a wrong URL or
+/// API key, so that the batches end up in the spool; other client errors mean the batch itself
+/// was not acceptable.
+fn classify_response(status: u16) -> ResponseClass {
+    match status {
+        200..=299 => ResponseClass::Success,
+        401 | 403 | 404 => ResponseClass::Misconfigured,
+        413 => ResponseClass::TooLarge,
+        429 | 503 => ResponseClass::Throttled,
+        408 | 500..=599 => ResponseClass::Retryable,
+        _ => ResponseClass::Permanent,
+    }
+}
+
+/// Reads the `Retry-After` header, given either in seconds or as an HTTP date.
+fn retry_after(headers: impl AsRef<Headers>) -> Option<Duration> {
+    match RetryAfter::from_headers(headers) {
+        Ok(retry_after) => retry_after.map(|retry_after| {
+            retry_after
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        }),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aino_config::MAX_BATCH_SIZE;
+    use crate::queue::{Admission, QueueConfig};
+    use crate::receipt::DeliveryHandle;
+    use crate::{DeadLetterHandler, OverflowPolicy, ReceiptHandler, SpoolConfig, Status};
+    use std::iter::repeat_with;
+    use std::thread;
+    use surf::http::Response;
+
+    fn create_config(send_interval: u32) -> AinoConfig {
+        AinoConfig {
+            send_interval,
+            ..AinoConfig::default()
+        }
+    }
+
+    fn create_trx() -> Transaction {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        Transaction::new(
+            "from".to_string(),
+            "to".to_string(),
+            "operation".to_string(),
+            Status::Success,
+            timestamp.as_millis(),
+            "flow_id".to_string(),
+            "integration_segment".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_can_send_batch_empty_buffer() {
+        let config = create_config(10);
+        let interval_start = Instant::now();
+        let buffer = TransactionBuffer::new();
+        assert!(!can_send_batch(&interval_start, &config, &buffer));
+    }
+
+    #[test]
+    fn test_can_send_batch_full_buffer() {
+        let config = create_config(10);
+        let interval_start = Instant::now();
+        let buffer: TransactionBuffer = repeat_with(create_trx).take(MAX_BATCH_SIZE + 1).collect();
+        assert!(can_send_batch(&interval_start, &config, &buffer));
+    }
+
+    #[test]
+    fn test_can_send_batch_timer() {
+        let config = create_config(10);
+        let interval_start = Instant::now();
+        let buffer: TransactionBuffer = repeat_with(create_trx).take(MAX_BATCH_SIZE - 1).collect();
+        thread::sleep(Duration::from_millis(11));
+        assert!(can_send_batch(&interval_start, &config, &buffer));
+    }
+
+    #[test]
+    fn test_can_send_batch_timer_with_empty_buffer() {
+        let config = create_config(10);
+        let interval_start = Instant::now();
+        let buffer = TransactionBuffer::new();
+        thread::sleep(Duration::from_millis(11));
+        assert!(!can_send_batch(&interval_start, &config, &buffer));
+    }
+
+    #[test]
+    fn test_create_batch_with_zero_transactions() {
+        let config = create_config(10);
+        let mut buffer = TransactionBuffer::new();
+        assert_eq!(
+            create_batch_request(&mut buffer, &config, &FlowGate::new(DeliveryOrder::Any))
+                .transactions
+                .len(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_create_batch_with_zero_max_batch_size() {
+        let mut config = create_config(60_000);
+        config.max_batch_size = 0;
+        let interval_start = Instant::now();
+        let mut buffer: TransactionBuffer = repeat_with(create_trx).take(2).collect();
+        assert!(can_send_batch(&interval_start, &config, &buffer));
+        let gate = FlowGate::new(DeliveryOrder::Any);
+        assert_eq!(
+            create_batch_request(&mut buffer, &config, &gate)
+                .transactions
+                .len(),
+            1
+        );
+        assert!(!can_send_batch(&interval_start, &config, &buffer));
+        assert_eq!(
+            create_batch_request(&mut buffer, &config, &gate)
+                .transactions
+                .len(),
+            1
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_create_batch_with_less_than_max_transactions() {
+        let config = create_config(10);
+        let mut buffer: TransactionBuffer =
+            repeat_with(create_trx).take(MAX_BATCH_SIZE - 1).collect();
+        assert_eq!(
+            create_batch_request(&mut buffer, &config, &FlowGate::new(DeliveryOrder::Any))
+                .transactions
+                .len(),
+            MAX_BATCH_SIZE - 1
+        );
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn test_create_batch_with_more_than_max_transactions() {
+        let config = create_config(10);
+        let mut buffer: TransactionBuffer =
+            repeat_with(create_trx).take(MAX_BATCH_SIZE + 1).collect();
+        assert_eq!(
+            create_batch_request(&mut buffer, &config, &FlowGate::new(DeliveryOrder::Any))
+                .transactions
+                .len(),
+            MAX_BATCH_SIZE
+        );
+        assert_eq!(buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_schedule_retry() {
+        let config = create_config(10);
+        let mut retries: Vec<PendingRetry> = Vec::new();
+        let batch = BatchRequest::new(vec![create_trx()], 0);
+        let queue = TransactionQueue::new(QueueConfig::default());
+        let due = Instant::now();
+        schedule_retry(&mut retries, &config, None, &queue, batch, 1, due);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].attempts, 1);
+        assert_eq!(retries[0].due, due);
+    }
+
+    #[test]
+    fn test_schedule_retry_gives_up_after_max_attempts() {
+        let config = create_config(10);
+        let mut retries: Vec<PendingRetry> = Vec::new();
+        let batch = BatchRequest::new(vec![create_trx()], 0);
+        let attempts = config.retry.max_attempts;
+        let queue = TransactionQueue::new(QueueConfig::default());
+        queue.reserve(1);
+        schedule_retry(
+            &mut retries,
+            &config,
+            None,
+            &queue,
+            batch,
+            attempts,
+            Instant::now(),
+        );
+        assert_eq!(queue.excess(), 0);
+        assert!(retries.is_empty());
+    }
+
+    #[test]
+    fn test_take_due_retries() {
+        let now = Instant::now();
+        let mut retries: Vec<PendingRetry> = vec![
+            PendingRetry {
+                batch: BatchRequest::new(vec![create_trx()], 0),
+                attempts: 1,
+                due: now,
+            },
+            PendingRetry {
+                batch: BatchRequest::new(vec![create_trx()], 0),
+                attempts: 2,
+                due: now + Duration::from_secs(60),
+            },
+        ];
+        let due = take_due_retries(&mut retries, now, &mut FlowGate::new(DeliveryOrder::Any));
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].attempts, 1);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].attempts, 2);
+    }
+
+    #[test]
+    fn test_give_up_stores_batch_in_spool() {
+        let directory =
+            std::env::temp_dir().join(format!("aino-agent-spool-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let mut spool = Spool::open(SpoolConfig {
+            directory: directory.clone(),
+            max_size: 1024 * 1024,
+            max_age: 60,
+        })
+        .unwrap();
+
+        let batch = BatchRequest::new(vec![create_trx()], 0);
+        let idempotency_key = batch.idempotency_key.clone();
+        give_up(Some(&mut spool), batch, 5);
+        let replay = |spool: &Spool| -> Vec<PendingRetry> {
+            let files = spool.files().unwrap();
+            let spooled = files.iter().filter_map(|path| spool.load(path));
+            spooled.map(replayed_retry).collect()
+        };
+        let retries = replay(&spool);
+        assert_eq!(retries.len(), 1);
+        assert_eq!(retries[0].batch.idempotency_key, idempotency_key);
+        assert_eq!(retries[0].attempts, 0);
+        assert_eq!(retries[0].batch.transactions.len(), 1);
+
+        // A replayed batch that fails again stays in its original spool file
+        let batch = retries.into_iter().next().unwrap().batch;
+        give_up(Some(&mut spool), batch, 5);
+        assert_eq!(replay(&spool).len(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_spool_replayed_as_room_frees_up() {
+        let directory =
+            std::env::temp_dir().join(format!("aino-agent-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let spool_config = SpoolConfig {
+            directory: directory.clone(),
+            max_size: 1024 * 1024,
+            max_age: 60,
+        };
+        let mut spool = Spool::open(spool_config.clone()).unwrap();
+        for _ in 0..3 {
+            spool.store("key", &[create_trx(), create_trx()]).unwrap();
+        }
+
+        let mut config = create_config(10);
+        config.queue.max_size = 4;
+        config.spool = Some(spool_config.clone());
+        let mut worker = create_worker(config);
+        // Only half of the queue is used for the spool
+        assert_eq!(worker.retries.len(), 1);
+        assert_eq!(worker.queue.excess(), 0);
+        worker.replay_spool();
+        assert_eq!(worker.retries.len(), 1);
+
+        let retry = worker.retries.pop().unwrap();
+        let id = start_send(&mut worker, retry.batch);
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        worker.replay_spool();
+        assert_eq!(worker.retries.len(), 1);
+        assert!(worker.next_replay.is_some());
+
+        // Stopping only waits for the batch already replayed, and leaves the rest in the spool
+        worker.handle_message(Msg::Cancel(None));
+        assert!(worker.next_replay.is_none());
+        let retry = worker.retries.pop().unwrap();
+        let id = start_send(&mut worker, retry.batch);
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        worker.replay_spool();
+        assert!(worker.retries.is_empty());
+        assert!(worker.is_finished());
+        assert_eq!(Spool::open(spool_config).unwrap().files().unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_spill_while_circuit_open() {
+        let directory =
+            std::env::temp_dir().join(format!("aino-agent-spill-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let spool_config = SpoolConfig {
+            directory: directory.clone(),
+            max_size: 1024 * 1024,
+            max_age: 60,
+        };
+        let mut config = create_config(60_000);
+        config.queue = QueueConfig {
+            max_size: 4,
+            overflow: OverflowPolicy::DropNewest,
+            block_timeout: 0,
+        };
+        config.spool = Some(spool_config.clone());
+        let mut worker = create_worker(config);
+        for _ in 0..worker.config.circuit_breaker.failure_threshold {
+            worker.breaker.on_failure(Instant::now());
+        }
+
+        // The queue never fills up, as the waiting `Transaction`s are moved into the spool
+        let (outcome, handle) = DeliveryHandle::new();
+        let mut outcome = Some(outcome);
+        for _ in 0..18 {
+            assert_eq!(worker.queue.admit(false).unwrap(), Admission::Accepted);
+            worker.handle_message(Msg::Trx(Box::new(create_trx()), outcome.take()));
+            worker.spill(Instant::now());
+        }
+        assert_eq!(worker.queue.dropped(), 0);
+        let spool = Spool::open(spool_config).unwrap();
+        let files = spool.files().unwrap();
+        let spooled: usize = files
+            .iter()
+            .filter_map(|path| spool.load(path))
+            .map(|batch| batch.transactions.len())
+            .sum();
+        assert_eq!(spooled, 18);
+        assert!(worker.buffer.is_empty());
+        assert_eq!(worker.replay.len(), files.len());
+
+        // Once the Data API is reachable again, the spilled batches are replayed
+        worker.breaker.on_success();
+        worker.replay_spool();
+        let retry = worker.retries.pop().unwrap();
+        let id = start_send(&mut worker, retry.batch);
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        assert_eq!(handle.wait(), DeliveryOutcome::Delivered { batch_id: None });
+        assert_eq!(spool.files().unwrap().len(), files.len() - 1);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_classify_response() {
+        assert_eq!(classify_response(200), ResponseClass::Success);
+        assert_eq!(classify_response(202), ResponseClass::Success);
+        assert_eq!(classify_response(408), ResponseClass::Retryable);
+        assert_eq!(classify_response(429), ResponseClass::Throttled);
+        assert_eq!(classify_response(500), ResponseClass::Retryable);
+        assert_eq!(classify_response(503), ResponseClass::Throttled);
+        assert_eq!(classify_response(413), ResponseClass::TooLarge);
+        assert_eq!(classify_response(400), ResponseClass::Permanent);
+        assert_eq!(classify_response(401), ResponseClass::Misconfigured);
+        assert_eq!(classify_response(403), ResponseClass::Misconfigured);
+        assert_eq!(classify_response(404), ResponseClass::Misconfigured);
+        assert_eq!(classify_response(422), ResponseClass::Permanent);
+    }
+
+    #[test]
+    fn test_is_timeout() {
+        assert!(is_timeout(&surf::Error::from(isahc::Error::Timeout)));
+        assert!(is_timeout(&surf::Error::from(io::Error::from(
+            io::ErrorKind::TimedOut
+        ))));
+        assert!(!is_timeout(&surf::Error::from(isahc::Error::ConnectFailed)));
+    }
+
+    #[test]
+    fn test_parse_batch_response() {
+        let response: BatchResponse = serde_json::from_str(r#"{"batch":"b-123"}"#).unwrap();
+        assert_eq!(response.batch, "b-123");
+    }
+
+    #[test]
+    fn test_retry_after_seconds() {
+        let mut res = Response::new(429);
+        res.insert_header("Retry-After", "120");
+        let delay = retry_after(&res).unwrap();
+        assert!(delay > Duration::from_secs(119) && delay < Duration::from_secs(121));
+    }
+
+    #[test]
+    fn test_retry_after_missing_or_invalid() {
+        let mut res = Response::new(503);
+        assert_eq!(retry_after(&res), None);
+        res.insert_header("Retry-After", "soon");
+        assert_eq!(retry_after(&res), None);
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut paused_until: Option<Instant> = None;
+        let until = pause(&mut paused_until, Duration::from_secs(30));
+        assert!(until > Instant::now() + Duration::from_secs(29));
+        assert_eq!(paused_until, Some(until));
+
+        // A shorter pause does not cut the current one short
+        pause(&mut paused_until, Duration::from_secs(1));
+        assert_eq!(paused_until, Some(until));
+    }
+
+    #[test]
+    fn test_split_batch() {
+        let mut retries: Vec<PendingRetry> = Vec::new();
+        let batch = BatchRequest::new(repeat_with(create_trx).take(5).collect(), 0);
+        let idempotency_key = batch.idempotency_key.clone();
+        split_batch(&mut retries, batch, 1);
+        assert_ne!(retries[0].batch.idempotency_key, idempotency_key);
+        assert_ne!(
+            retries[0].batch.idempotency_key,
+            retries[1].batch.idempotency_key
+        );
+        assert_eq!(retries.len(), 2);
+        assert_eq!(retries[0].batch.transactions.len(), 2);
+        assert_eq!(retries[1].batch.transactions.len(), 3);
+        assert!(retries.iter().all(|retry| retry.attempts == 1));
+
+        let batch = retries.pop().unwrap().batch;
+        split_batch(&mut retries, batch, 1);
+        assert_eq!(retries.len(), 3);
+        assert_eq!(retries[1].batch.transactions.len(), 1);
+        assert_eq!(retries[2].batch.transactions.len(), 2);
+    }
+
+    #[test]
+    fn test_can_send_batch_full_bytes() {
+        let mut config = create_config(10_000);
+        let interval_start = Instant::now();
+        let buffer: TransactionBuffer = repeat_with(create_trx).take(10).collect();
+        assert!(!can_send_batch(&interval_start, &config, &buffer));
+        config.max_batch_bytes = buffer.bytes() - 1;
+        assert!(can_send_batch(&interval_start, &config, &buffer));
+    }
+
+    #[test]
+    fn test_create_batch_limited_by_bytes() {
+        let mut config = create_config(10);
+        let mut buffer: TransactionBuffer = repeat_with(create_trx).take(10).collect();
+        config.max_batch_bytes = buffer.bytes() / 2;
+        let batch = create_batch_request(&mut buffer, &config, &FlowGate::new(DeliveryOrder::Any));
+        assert!(!batch.transactions.is_empty());
+        assert!(batch.transactions.len() < 5);
+        assert!(serde_json::to_vec(&batch).unwrap().len() <= config.max_batch_bytes);
+    }
+
+    #[test]
+    fn test_drop_oldest() {
+        let queue = TransactionQueue::new(QueueConfig {
+            max_size: 2,
+            overflow: OverflowPolicy::DropOldest,
+            block_timeout: 0,
+        });
+        let mut buffer = TransactionBuffer::new();
+        let mut priority = TransactionBuffer::new();
+        queue.admit(true).unwrap();
+        priority.push_back(create_trx(), 0);
+        for seq in 1..4 {
+            queue.admit(false).unwrap();
+            buffer.push_back(create_trx(), seq);
+            drop_oldest(&queue, &mut buffer, &mut priority);
+        }
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer.front_seq(), Some(3));
+        assert_eq!(priority.len(), 1);
+        assert_eq!(queue.excess(), 0);
+        assert_eq!(queue.dropped(), 2);
+
+        // Urgent `Transaction`s are dropped only when there are no routine ones left
+        queue.admit(true).unwrap();
+        priority.push_back(create_trx(), 4);
+        queue.admit(true).unwrap();
+        priority.push_back(create_trx(), 5);
+        let dropped = drop_oldest(&queue, &mut buffer, &mut priority);
+        assert_eq!(dropped.len(), 2);
+        assert!(buffer.is_empty());
+        assert_eq!(priority.front_seq(), Some(4));
+    }
+
+    fn create_worker(config: AinoConfig) -> Worker {
+        let (feedback, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let (finished, _) = mpsc::channel();
+        let queue = Arc::new(TransactionQueue::new(config.queue.clone()));
+        Worker::new(
+            None,
+            config,
+            receiver,
+            feedback,
+            finished,
+            Arc::new(Mutex::new(CircuitState::Closed)),
+            queue,
+        )
+        .unwrap()
+    }
+
+    /// Tracks the batch as being sent without actually sending it.
+    fn start_send(worker: &mut Worker, batch: BatchRequest) -> u64 {
+        let id = worker.next_send_id;
+        worker.next_send_id += 1;
+        worker.in_flight.insert(id, InFlight { batch, attempts: 0 });
+        id
+    }
+
+    #[test]
+    fn test_delivered() {
+        let mut worker = create_worker(create_config(10));
+        worker.queue.reserve(2);
+        let id = start_send(
+            &mut worker,
+            BatchRequest::new(repeat_with(create_trx).take(2).collect(), 0),
+        );
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        assert_eq!(worker.delivered, 2);
+        assert!(worker.in_flight.is_empty());
+        assert_eq!(worker.queue.excess(), 0);
+    }
+
+    #[test]
+    fn test_records_name_the_destination() {
+        let destinations = Arc::new(Mutex::new(Vec::new()));
+        let mut config = create_config(10);
+        let receipts = destinations.clone();
+        config.receipt_handler = Some(ReceiptHandler::new(move |receipt| {
+            receipts.lock().unwrap().push(receipt.destination.clone());
+        }));
+        let letters = destinations.clone();
+        config.dead_letter_handler = Some(DeadLetterHandler::new(move |letter| {
+            letters.lock().unwrap().push(letter.destination.clone());
+        }));
+        let mut worker = create_worker(config);
+        worker.destination = Some("tenant".to_string());
+
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        worker.handle_message(Msg::Sent(id, SendResult::Rejected(400, String::new())));
+
+        let tenant = Some("tenant".to_string());
+        assert_eq!(*destinations.lock().unwrap(), vec![tenant.clone(), tenant]);
+    }
+
+    #[test]
+    fn test_failed_send_is_retried() {
+        let mut worker = create_worker(create_config(10));
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Failed(AinoError::new(String::new())),
+        ));
+        assert_eq!(worker.retries.len(), 1);
+        assert_eq!(worker.retries[0].attempts, 1);
+    }
+
+    #[test]
+    fn test_throttled_send_uses_up_attempts() {
+        let mut worker = create_worker(create_config(10));
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        worker.handle_message(Msg::Sent(id, SendResult::Throttled(429, None)));
+        assert!(worker.paused_until.is_some());
+        assert_eq!(worker.retries.len(), 1);
+        assert_eq!(worker.retries[0].attempts, 1);
+
+        // A Data API that keeps throttling is given up on after the last attempt
+        let retry = worker.retries.pop().unwrap();
+        let attempts = worker.config.retry.max_attempts - 1;
+        worker.in_flight.insert(
+            id,
+            InFlight {
+                batch: retry.batch,
+                attempts,
+            },
+        );
+        worker.handle_message(Msg::Sent(id, SendResult::Throttled(429, None)));
+        assert!(worker.retries.is_empty());
+        assert!(worker.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_unavailable_opens_circuit() {
+        let mut worker = create_worker(create_config(10));
+        for _ in 0..worker.config.circuit_breaker.failure_threshold {
+            let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+            worker.handle_message(Msg::Sent(id, SendResult::Throttled(503, None)));
+        }
+        assert_eq!(worker.breaker.state(), CircuitState::Open);
+
+        // A 503 with `Retry-After` comes from the Data API itself
+        let mut worker = create_worker(create_config(10));
+        for _ in 0..worker.config.circuit_breaker.failure_threshold {
+            let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+            let retry_after = Some(Duration::from_millis(1));
+            worker.handle_message(Msg::Sent(id, SendResult::Throttled(503, retry_after)));
+        }
+        assert_eq!(worker.breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_abandon_at_stop_deadline() {
+        let mut worker = create_worker(create_config(10_000));
+        worker.queue.reserve(6);
+        let in_flight = BatchRequest::new(repeat_with(create_trx).take(2).collect(), 0);
+        let id = start_send(&mut worker, in_flight);
+        worker.retries.push(PendingRetry {
+            batch: BatchRequest::new(repeat_with(create_trx).take(3).collect(), 0),
+            attempts: 1,
+            due: Instant::now() + Duration::from_secs(60),
+        });
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        worker.handle_message(Msg::Cancel(Some(Instant::now())));
+        assert!(!worker.is_finished());
+
+        worker.abandon();
+        assert!(worker.is_finished());
+        assert_eq!(worker.undelivered.len(), 6);
+        assert_eq!(worker.queue.excess(), 0);
+
+        // The late result of an abandoned send is ignored
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        assert_eq!(worker.delivered, 0);
+    }
+
+    #[test]
+    fn test_abandon_stores_in_spool() {
+        let directory =
+            std::env::temp_dir().join(format!("aino-agent-abandon-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let spool_config = SpoolConfig {
+            directory: directory.clone(),
+            max_size: 1024 * 1024,
+            max_age: 60,
+        };
+        Spool::open(spool_config.clone())
+            .unwrap()
+            .store("key", &[create_trx(), create_trx()])
+            .unwrap();
+        // The spool files are named after the millisecond they were written in
+        thread::sleep(Duration::from_millis(2));
+
+        let mut config = create_config(10_000);
+        config.spool = Some(spool_config);
+        let mut worker = create_worker(config);
+        let replayed = worker.retries.pop().unwrap();
+        start_send(&mut worker, replayed.batch);
+        worker.queue.reserve(3);
+        let id = start_send(
+            &mut worker,
+            BatchRequest::new(repeat_with(create_trx).take(2).collect(), 0),
+        );
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Failed(AinoError::new(String::new())),
+        ));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        worker.handle_message(Msg::Cancel(Some(Instant::now())));
+
+        worker.abandon();
+        assert!(worker.is_finished());
+        assert!(worker.undelivered.is_empty());
+        assert_eq!(worker.spooled, 5);
+        assert_eq!(worker.queue.excess(), 0);
+        // The replayed batch stayed in its file, and the others were stored next to it
+        let spool = worker.spool.as_ref().unwrap();
+        assert_eq!(spool.files().unwrap().len(), 3);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_earliest_stop_deadline_wins() {
+        let mut worker = create_worker(create_config(10));
+        let deadline = Instant::now() + Duration::from_secs(1);
+        worker.handle_message(Msg::Cancel(Some(deadline)));
+        worker.handle_message(Msg::Cancel(None));
+        worker.handle_message(Msg::Cancel(Some(deadline + Duration::from_secs(1))));
+        assert_eq!(worker.stop_deadline, Some(deadline));
+    }
+
+    #[test]
+    fn test_flush_waits_for_earlier_transactions() {
+        let mut worker = create_worker(create_config(10_000));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        let (done, mut receiver) = oneshot::channel();
+        worker.handle_message(Msg::Flush(done));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        assert!(worker.must_send_buffer());
+
+        let first_seq = worker.buffer.front_seq().unwrap();
+        let batch = BatchRequest::new(
+            vec![
+                worker.buffer.pop_front().unwrap(),
+                worker.buffer.pop_front().unwrap(),
+            ],
+            first_seq,
+        );
+        // The `Transaction` added after the flush is not sent right away
+        assert!(!worker.must_send_buffer());
+
+        let id = start_send(&mut worker, batch);
+        worker.complete_flushes();
+        assert!(receiver.try_recv().is_err());
+
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        worker.complete_flushes();
+        assert!(receiver.try_recv().is_ok());
+        assert_eq!(worker.buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_flush_waits_for_split_halves() {
+        let mut worker = create_worker(create_config(10_000));
+        let (done, mut receiver) = oneshot::channel();
+        let id = start_send(
+            &mut worker,
+            BatchRequest::new(repeat_with(create_trx).take(4).collect(), 1),
+        );
+        worker.next_seq = 5;
+        worker.handle_message(Msg::Flush(done));
+        worker.handle_message(Msg::Sent(id, SendResult::TooLarge));
+        assert_eq!(worker.retries[1].batch.first_seq, 3);
+
+        worker.retries.remove(0);
+        worker.complete_flushes();
+        assert!(receiver.try_recv().is_err());
+        worker.retries.clear();
+        worker.complete_flushes();
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_flush_with_nothing_pending() {
+        let mut worker = create_worker(create_config(10));
+        let (done, mut receiver) = oneshot::channel();
+        worker.handle_message(Msg::Flush(done));
+        worker.complete_flushes();
+        assert!(receiver.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_max_in_flight() {
+        let mut config = create_config(10);
+        config.max_in_flight = 2;
+        let mut worker = create_worker(config);
+        let now = Instant::now();
+        worker.retries.push(PendingRetry {
+            batch: BatchRequest::new(vec![create_trx()], 0),
+            attempts: 1,
+            due: now,
+        });
+        start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        assert!(worker.can_send_more());
+        assert_eq!(worker.next_deadline(now), Some(now));
+
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        assert!(!worker.can_send_more());
+        assert_eq!(worker.next_deadline(now), None);
+
+        // The due retry stays queued until a send finishes
+        worker.dispatch(now);
+        assert_eq!(worker.retries.len(), 1);
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        assert!(worker.can_send_more());
+    }
+
+    #[test]
+    fn test_tracked_transaction_delivered() {
+        let mut worker = create_worker(create_config(10));
+        let (outcome, handle) = DeliveryHandle::new();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), Some(outcome)));
+        let trx = worker.buffer.pop_front().unwrap();
+        let id = start_send(&mut worker, BatchRequest::new(vec![trx, create_trx()], 1));
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Delivered(Some("b-1".to_string())),
+        ));
+        assert_eq!(
+            handle.wait(),
+            DeliveryOutcome::Delivered {
+                batch_id: Some("b-1".to_string())
+            }
+        );
+        assert!(worker.outcomes.is_empty());
+    }
+
+    #[test]
+    fn test_tracked_clones_resolved_separately() {
+        let mut worker = create_worker(create_config(10));
+        let trx = create_trx();
+        let (first, first_handle) = DeliveryHandle::new();
+        worker.handle_message(Msg::Trx(Box::new(trx.clone()), Some(first)));
+        let (second, second_handle) = DeliveryHandle::new();
+        worker.handle_message(Msg::Trx(Box::new(trx), Some(second)));
+
+        let first = BatchRequest::new(vec![worker.buffer.pop_front().unwrap()], 1);
+        let second = BatchRequest::new(vec![worker.buffer.pop_front().unwrap()], 2);
+        let first_id = start_send(&mut worker, first);
+        let second_id = start_send(&mut worker, second);
+        worker.handle_message(Msg::Sent(
+            second_id,
+            SendResult::Delivered(Some("b-2".to_string())),
+        ));
+        worker.handle_message(Msg::Sent(
+            first_id,
+            SendResult::Rejected(400, "invalid".to_string()),
+        ));
+
+        assert_eq!(
+            second_handle.wait(),
+            DeliveryOutcome::Delivered {
+                batch_id: Some("b-2".to_string())
+            }
+        );
+        assert!(matches!(
+            first_handle.wait(),
+            DeliveryOutcome::Rejected { status: 400, .. }
+        ));
+    }
+
+    #[test]
+    fn test_tracked_transaction_rejected() {
+        let mut worker = create_worker(create_config(10));
+        let (outcome, handle) = DeliveryHandle::new();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), Some(outcome)));
+        let trx = worker.buffer.pop_front().unwrap();
+        let id = start_send(&mut worker, BatchRequest::new(vec![trx], 1));
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Rejected(400, "invalid".to_string()),
+        ));
+        assert_eq!(
+            handle.wait(),
+            DeliveryOutcome::Rejected {
+                status: 400,
+                reason: "invalid".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_tracked_transaction_dropped_after_retries() {
+        let mut worker = create_worker(create_config(10));
+        let (outcome, handle) = DeliveryHandle::new();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), Some(outcome)));
+        let trx = worker.buffer.pop_front().unwrap();
+        let id = start_send(&mut worker, BatchRequest::new(vec![trx], 1));
+        worker.in_flight.get_mut(&id).unwrap().attempts = worker.config.retry.max_attempts - 1;
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Failed(AinoError::new(String::new())),
+        ));
+        assert!(worker.retries.is_empty());
+        assert_eq!(
+            handle.wait(),
+            DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::Other
+            }
+        );
+    }
+
+    #[test]
+    fn test_tracked_transaction_dropped_oldest() {
+        let mut config = create_config(10);
+        config.queue = QueueConfig {
+            max_size: 1,
+            overflow: OverflowPolicy::DropOldest,
+            block_timeout: 0,
+        };
+        let mut worker = create_worker(config);
+        let (outcome, handle) = DeliveryHandle::new();
+        worker.queue.admit(false).unwrap();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), Some(outcome)));
+        worker.queue.admit(false).unwrap();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        assert_eq!(
+            handle.wait(),
+            DeliveryOutcome::Dropped {
+                reason: AinoErrorKind::QueueFull
+            }
+        );
+        assert_eq!(worker.buffer.len(), 1);
+    }
+
+    #[test]
+    fn test_priority_lane() {
+        let mut worker = create_worker(create_config(10_000));
+        let mut failure = create_trx();
+        failure.status = Status::Failure;
+        let mut urgent = create_trx();
+        urgent.mark_urgent();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        assert!(worker.next_deadline(Instant::now()) > Some(Instant::now()));
+
+        worker.handle_message(Msg::Trx(Box::new(failure), None));
+        worker.handle_message(Msg::Trx(Box::new(urgent), None));
+        assert_eq!(worker.priority.len(), 2);
+        assert_eq!(worker.buffer.len(), 1);
+        let now = Instant::now();
+        assert_eq!(worker.next_deadline(now), Some(now));
+        assert_eq!(worker.oldest_pending_seq(), Some(1));
+    }
+
+    fn create_flow_trx(flow_id: &str) -> Transaction {
+        let mut trx = create_trx();
+        trx.flow_id = flow_id.to_string();
+        trx
+    }
+
+    #[test]
+    fn test_per_flow_waits_for_earlier_batch() {
+        let mut config = create_config(10_000);
+        config.ordering = DeliveryOrder::PerFlow;
+        let mut worker = create_worker(config);
+        let id = start_send(
+            &mut worker,
+            BatchRequest::new(vec![create_flow_trx("a")], 0),
+        );
+        worker.handle_message(Msg::Trx(Box::new(create_flow_trx("a")), None));
+        worker.handle_message(Msg::Trx(Box::new(create_flow_trx("b")), None));
+        worker.handle_message(Msg::Cancel(None));
+        let now = Instant::now();
+        assert_eq!(worker.next_deadline(now), Some(now));
+
+        // The unrelated flow goes ahead, the later `Transaction` of the busy flow waits
+        let gate = worker.flow_gate();
+        let batch = create_batch_request(&mut worker.buffer, &worker.config, &gate);
+        assert_eq!(batch.transactions.len(), 1);
+        assert_eq!(batch.transactions[0].flow_id, "b");
+        assert_eq!(batch.first_seq, 2);
+        assert_eq!(worker.next_deadline(now), None);
+
+        worker.handle_message(Msg::Sent(id, SendResult::Delivered(None)));
+        assert_eq!(worker.next_deadline(now), Some(now));
+    }
+
+    #[test]
+    fn test_per_flow_retries_in_order() {
+        let mut config = create_config(10_000);
+        config.ordering = DeliveryOrder::PerFlow;
+        let mut worker = create_worker(config);
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        worker.retries.push(PendingRetry {
+            batch: BatchRequest::new(vec![create_flow_trx("a")], 2),
+            attempts: 1,
+            due: now,
+        });
+        worker.retries.push(PendingRetry {
+            batch: BatchRequest::new(vec![create_flow_trx("a")], 1),
+            attempts: 1,
+            due: later,
+        });
+        assert_eq!(worker.next_deadline(now), Some(later));
+
+        worker.dispatch(now);
+        assert_eq!(worker.retries.len(), 2);
+        assert!(worker.in_flight.is_empty());
+    }
+
+    #[test]
+    fn test_per_flow_urgent_takes_flow_along() {
+        let mut config = create_config(10_000);
+        config.ordering = DeliveryOrder::PerFlow;
+        let mut worker = create_worker(config);
+        let mut urgent = create_flow_trx("a");
+        urgent.mark_urgent();
+        worker.handle_message(Msg::Trx(Box::new(create_flow_trx("a")), None));
+        worker.handle_message(Msg::Trx(Box::new(create_flow_trx("b")), None));
+        worker.handle_message(Msg::Trx(Box::new(urgent), None));
+        assert_eq!(worker.priority.len(), 2);
+        assert_eq!(worker.priority.front_seq(), Some(1));
+        assert_eq!(worker.buffer.len(), 1);
+        assert_eq!(worker.buffer.front_seq(), Some(2));
+    }
+
+    #[test]
+    fn test_next_deadline_idle() {
+        let worker = create_worker(create_config(10_000));
+        assert_eq!(worker.next_deadline(Instant::now()), None);
+    }
+
+    #[test]
+    fn test_next_deadline_buffered() {
+        let mut worker = create_worker(create_config(10_000));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        let now = Instant::now();
+        assert_eq!(
+            worker.next_deadline(now),
+            Some(worker.interval_start + Duration::from_secs(10))
+        );
+
+        worker.handle_message(Msg::Cancel(None));
+        assert_eq!(worker.next_deadline(now), Some(now));
+    }
+
+    #[test]
+    fn test_next_deadline_retry() {
+        let mut worker = create_worker(create_config(10_000));
+        let now = Instant::now();
+        let due = now + Duration::from_secs(1);
+        worker.retries.push(PendingRetry {
+            batch: BatchRequest::new(vec![create_trx()], 0),
+            attempts: 1,
+            due,
+        });
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        assert_eq!(worker.next_deadline(now), Some(due));
+    }
+
+    #[test]
+    fn test_next_deadline_paused() {
+        let mut worker = create_worker(create_config(10));
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        let now = Instant::now();
+        let until = now + Duration::from_secs(30);
+        worker.paused_until = Some(until);
+        assert_eq!(worker.next_deadline(now), Some(until));
+    }
+
+    #[test]
+    fn test_next_deadline_circuit_open() {
+        let mut config = create_config(10);
+        config.circuit_breaker.failure_threshold = 1;
+        let mut worker = create_worker(config);
+        let now = Instant::now();
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        let id = start_send(&mut worker, BatchRequest::new(vec![create_trx()], 0));
+        worker.handle_message(Msg::Sent(
+            id,
+            SendResult::Failed(AinoError::new(String::new())),
+        ));
+        let reopens_at = worker.breaker.reopens_at().unwrap();
+        assert!(reopens_at > now);
+        assert_eq!(worker.next_deadline(now), Some(reopens_at));
+
+        // Nothing is due while the probe batch is being sent
+        assert!(worker.breaker.try_acquire(reopens_at));
+        assert_eq!(worker.next_deadline(reopens_at), None);
+    }
+
+    #[test]
+    fn test_is_finished() {
+        let mut worker = create_worker(create_config(10));
+        assert!(!worker.is_finished());
+        worker.handle_message(Msg::Trx(Box::new(create_trx()), None));
+        worker.handle_message(Msg::Cancel(None));
+        assert!(!worker.is_finished());
+        worker.buffer.pop_front();
+        assert!(worker.is_finished());
+    }
+}